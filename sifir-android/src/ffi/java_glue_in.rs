@@ -1,12 +1,21 @@
 use jni_sys::*;
 use logger::{log, Logger};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use tor::{
     hidden_service::{HiddenServiceDataHandler, HiddenServiceHandler},
+    onion::client_auth::ClientAuthKeypair,
+    onion::disk::DiskHiddenService,
+    onion::health::{HealthMonitor, HealthMonitorParam, HealthObserver, ServiceHealth},
+    onion::keys,
+    onion::keystore::{PassphraseWrapper, WrappingKey},
+    onion::migration::OnionMigration,
+    onion::seed,
+    onion::vanity::VanitySearch,
+    onion::OnionAddress,
     tcp_stream::{DataObserver, TcpSocksStream},
-    BootstrapPhase, OwnedTorService, OwnedTorServiceBootstrapPhase, TorHiddenService,
-    TorHiddenServiceParam, TorServiceParam,
+    BootstrapPhase, HiddenServicePort, OwnedTorService, OwnedTorServiceBootstrapPhase,
+    TorErrors, TorHiddenService, TorHiddenServiceParam, TorServiceParam,
 };
 
 /// Java callback interface for DataObserver callback used in TcpStreams, HiddenService etc..
@@ -34,6 +43,71 @@ impl DataObserver for Observer {
     }
 }
 
+// health changes are sent as JSON ServiceHealth to onData
+impl HealthObserver for Observer {
+    fn on_health_change(&self, health: &ServiceHealth) {
+        self.cb.on_data(serde_json::to_string(health).unwrap());
+    }
+}
+
+fn create_hidden_service_with_mappings<F>(
+    owned: &mut OwnedTorService,
+    mut param: TorHiddenServiceParam,
+    secret_key: String,
+    create: F,
+) -> Result<TorHiddenService, String>
+where
+    F: FnOnce(&mut OwnedTorService, TorHiddenServiceParam) -> Result<TorHiddenService, TorErrors>,
+{
+    // base64 of the key or an ED25519-V3: blob as used by the control port
+    let hs_key = match secret_key.len() {
+        0 => Ok(None),
+        _ => keys::from_control_blob(&secret_key).map(Some),
+    };
+
+    match hs_key {
+        Ok(key) => {
+            param.secret_key = key;
+            create(owned, param).map_err(|e| format!("{:#?}", e))
+        }
+        Err(e) => Err(format!("{:#?}", e)),
+    }
+}
+
+/// options_json of create_hidden_service_with_options: a TorHiddenServiceParam and how to create it
+#[derive(Deserialize)]
+struct HiddenServiceOptions {
+    #[serde(flatten)]
+    param: TorHiddenServiceParam,
+    wait_ms: Option<u64>,
+    import_dir: Option<String>,
+    seed: Option<String>,
+    seed_label: Option<String>,
+}
+
+/// Key the SDK reads or derives itself for options, None to use secret_key
+fn sdk_secret_key(options: &HiddenServiceOptions) -> Result<Option<[u8; 64]>, TorErrors> {
+    match (&options.import_dir, &options.seed) {
+        (Some(_), Some(_)) => Err(TorErrors::HiddenServiceParamError(String::from(
+            "import_dir and seed can't be used together",
+        ))),
+        (Some(dir), None) => keys::read_hidden_service_dir(dir).map(Some),
+        (None, Some(seed)) => {
+            let label = options.seed_label.as_deref().ok_or_else(|| {
+                TorErrors::HiddenServiceParamError(String::from("seed needs a seed_label"))
+            })?;
+            derive_seed_key(seed, label).map(Some)
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+fn derive_seed_key(seed: &str, label: &str) -> Result<[u8; 64], TorErrors> {
+    let seed_bytes = base64::decode(seed.trim())
+        .map_err(|e| TorErrors::OnionKeyError(format!("Seed is not base64: {}", e)))?;
+    seed::derive_secret_key(&seed_bytes, label)
+}
+
 /// Hiden Service Handler
 foreign_class!(class HiddenServiceHandler {
     self_type HiddenServiceHandler;
@@ -45,6 +119,85 @@ foreign_class!(class HiddenServiceHandler {
     }
 });
 
+/// Background search for an onion key whose address starts with prefix (a-z, 2-7), on threads workers or all cores if 0
+foreign_class!(class VanitySearch {
+    self_type VanitySearch;
+    constructor start(prefix: String, threads: u32)->Result<VanitySearch,String> {
+        let threads = if threads == 0 { None } else { Some(threads as usize) };
+        VanitySearch::start(&prefix, threads).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// JSON {"attempts":u64,"elapsed_ms":u64,"keys_per_sec":u64,"expected_attempts":f64,"eta_ms":u64|null,"found":bool,"cancelled":bool}
+    fn get_progress(&self) -> String {
+        serde_json::to_string(&this.progress()).unwrap()
+    }
+    fn cancel(&self) {
+        this.cancel()
+    }
+    /// Blocks until a key is found, explicitly exports it as JSON {"onion":String,"secret_key":String} with the base64 key to pass as secret_key
+    fn wait(&mut self) -> Result<String,String> {
+        let key = this.wait().map_err(|e| { format!("{:#?}",e)})?;
+        Ok(serde_json::json!({ "onion": key.onion, "secret_key": base64::encode(&key.secret_key[..]) }).to_string())
+    }
+});
+
+/// Background checks of the hosted onions, started with OwnedTorService.start_health_monitor
+foreign_class!(class HealthMonitor {
+    self_type HealthMonitor;
+    /// JSON array of {"onion":String,"status":"Unknown"|"Reachable"|"Unreachable"|"Republished","consecutive_failures":u32,"republished":u32,"availability":f64,"history":[{"at_ms":u64,"outcome":String,"latency_ms":u64|null}]}
+    fn get_report(&self) -> String {
+        serde_json::to_string(&this.report()).unwrap()
+    }
+    fn stop(&mut self) {
+        this.stop()
+    }
+});
+
+/// Onion key utilities that work without a running daemon, secret keys are base64 or ED25519-V3: blobs and messages and signatures base64
+foreign_class!(class OnionKeys {
+    /// Explicitly exports a new key as JSON {"onion":String,"secret_key":String} with the base64 key to pass as secret_key
+    fn generate() -> Result<String,String> {
+        let secret_key = keys::generate_secret_key();
+        let onion = keys::onion_address(&secret_key).map_err(|e| { format!("{:#?}",e)})?;
+        Ok(serde_json::json!({ "onion": onion.to_string(), "secret_key": base64::encode(&secret_key[..]) }).to_string())
+    }
+    fn onion_address(secret_key: String) -> Result<String,String> {
+        keys::from_control_blob(&secret_key).and_then(|secret_key| keys::onion_address(&secret_key)).map(|onion| onion.to_string()).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// Onion address OwnedTorService.create_hidden_service_with_options creates for seed and label
+    fn derive_onion_address(seed: String, label: String) -> Result<String,String> {
+        derive_seed_key(&seed, &label).and_then(|secret_key| keys::onion_address(&secret_key)).map(|onion| onion.to_string()).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// Error says what is wrong with the address
+    fn validate_onion_address(onion: String) -> Result<(),String> {
+        onion.parse::<OnionAddress>().map(|_| ()).map_err(|e| { format!("{:#?}",e)})
+    }
+    fn sign(secret_key: String, message: String) -> Result<String,String> {
+        let secret_key = keys::from_control_blob(&secret_key).map_err(|e| { format!("{:#?}",e)})?;
+        let message = base64::decode(message.trim()).map_err(|e| { format!("{:#?}",e)})?;
+        keys::sign(&secret_key, &message).map(|signature| base64::encode(&signature[..])).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// True only if signature was made over message with the key of onion
+    fn verify(onion: String, message: String, signature: String) -> Result<bool,String> {
+        let onion: OnionAddress = onion.parse().map_err(|e| { format!("{:#?}",e)})?;
+        let message = base64::decode(message.trim()).map_err(|e| { format!("{:#?}",e)})?;
+        let signature = base64::decode(signature.trim()).map_err(|e| { format!("{:#?}",e)})?;
+        Ok(onion.verify(&message, &signature))
+    }
+    /// Checks both signatures of a JSON OnionMigration received from a peer, see OwnedTorService.rotate_hidden_service
+    fn verify_migration(migration_json: String) -> Result<(),String> {
+        let migration: OnionMigration = serde_json::from_str(&migration_json).map_err(|e| { format!("{:#?}",e)})?;
+        migration.verify().map_err(|e| { format!("{:#?}",e)})
+    }
+    /// Verifies a JSON OnionMigration and returns the address to keep for stored: the new onion (with the port of stored) if stored is the old one, stored unchanged otherwise
+    fn migrate_address(migration_json: String, stored: String) -> Result<String,String> {
+        let migration: OnionMigration = serde_json::from_str(&migration_json).map_err(|e| { format!("{:#?}",e)})?;
+        match migration.migrate(&stored).map_err(|e| { format!("{:#?}",e)})? {
+            Some(onion) => Ok(onion.to_string()),
+            None => Ok(stored),
+        }
+    }
+});
+
 /// Tor Hidden Service, cannot be constructed directly
 foreign_class!(class TorHiddenService {
     self_type TorHiddenService;
@@ -52,8 +205,34 @@ foreign_class!(class TorHiddenService {
     fn get_onion_url(&self)->String{
         this.onion_url.to_string()
     }
-    fn get_secret_b64(&self)->String{
-        base64::encode(this.secret_key).into()
+});
+
+/// x25519 keypair for onion service client authorization, constructing it generates a new one
+foreign_class!(class ClientAuthKeypair {
+    self_type ClientAuthKeypair;
+    constructor ClientAuthKeypair::generate()->ClientAuthKeypair;
+    fn get_public_key(&self)->String{
+        this.public_key.clone()
+    }
+    fn get_private_key(&self)->String{
+        this.private_key.clone()
+    }
+});
+
+/// TcpStream Android Interface
+foreign_class!(class TcpSocksStream {
+    self_type TcpSocksStream;
+    constructor new(target:String,socks_proxy:String,timeout_ms:u64)->Result<TcpSocksStream,String> {
+      TcpSocksStream::new_timeout(target,socks_proxy,timeout_ms,false).map_err(|e| { format!("{:#?}",e) })
+    }
+    fn on_data(&mut self,cb:Box<dyn DataObserver>)->Result<(),String>{
+      this.set_data_handler(Observer{
+       cb,
+      }).unwrap();
+      this.read_line_async().map_err(|e| { format!("{:#?}",e)})
+    }
+    fn send_data(&mut self, msg:String,timeout:u64)->Result<(),String>{
+        this.send_data(msg, Some(Duration::new(timeout, 0))).map_err(|e| { format!("{:#?}",e) })
     }
 });
 
@@ -61,6 +240,32 @@ foreign_class!(class TorHiddenService {
 foreign_class!(class TorServiceParam {
     self_type TorServiceParam;
     constructor TorServiceParam::new(data_dir:&str,socks_port:u16,bootstap_timeout_ms: u64)->TorServiceParam;
+    fn set_onion_only(&mut self, onion_only: bool) {
+        this.onion_only = onion_only;
+    }
+    fn set_dns_port(&mut self, dns_port: u16) {
+        this.dns_port = Some(dns_port);
+    }
+    fn set_trans_port(&mut self, trans_port: u16) {
+        this.trans_port = Some(trans_port);
+    }
+    fn set_automap_hosts_on_resolve(&mut self, automap: bool) {
+        this.automap_hosts_on_resolve = automap;
+    }
+    fn set_virtual_addr_network_ipv4(&mut self, network: String) {
+        this.virtual_addr_network_ipv4 = Some(network);
+    }
+    fn set_http_tunnel_port(&mut self, http_tunnel_port: u16) {
+        this.http_tunnel_port = Some(http_tunnel_port);
+    }
+    /// service_json: {"name": String, "ports": [{"hs_port":80,"target":"127.0.0.1:8080"}],
+    /// "intro_dos_defense": {"rate_per_sec": u32, "burst_per_sec": u32}}, intro_dos_defense optional
+    /// Tor publishes it from a HiddenServiceDir, see OwnedTorService.disk_hidden_service
+    fn add_disk_hidden_service(&mut self, service_json: String) -> Result<(),String> {
+        let service: DiskHiddenService = serde_json::from_str(&service_json).map_err(|e| { format!("{:#?}",e)})?;
+        this.disk_hidden_services.push(service);
+        Ok(())
+    }
 });
 
 foreign_class!(class OwnedTorService {
@@ -72,6 +277,29 @@ foreign_class!(class OwnedTorService {
     fn getSocksPort(&self)-> u16{
         this.socks_port
     }
+    fn isOnionOnly(&self)-> bool{
+        this.onion_only
+    }
+    /// TcpSocksStream through our SocksPort, non onion targets are rejected in onion only mode
+    fn tcp_stream(&self, target: String, timeout_ms: u64)->Result<TcpSocksStream,String>{
+        this.tcp_stream(target, timeout_ms).map_err(|e| { format!("{:#?}",e)})
+    }
+    fn getTorVersion(&self)-> String{
+        this.tor_version.clone()
+    }
+    fn getDnsListener(&self)-> String{
+        this.dns_listener.map(|addr| addr.to_string()).unwrap_or_default()
+    }
+    fn getTransListener(&self)-> String{
+        this.trans_listener.map(|addr| addr.to_string()).unwrap_or_default()
+    }
+    fn getHttpTunnelListener(&self)-> String{
+        this.http_tunnel_listener.map(|addr| addr.to_string()).unwrap_or_default()
+    }
+    /// Onion of a service added with TorServiceParam.add_disk_hidden_service, by name
+    fn disk_hidden_service(&self, name: String) -> Result<String,String> {
+        this.disk_hidden_service(&name).map(|onion| onion.to_string()).map_err(|e| { format!("{:#?}",e)})
+    }
     fn shutdown(&mut self)->Result<(),String>{
         this.shutdown().map_err(|e| { format!("{:#?}",e) })
     }
@@ -86,46 +314,122 @@ foreign_class!(class OwnedTorService {
        }
     }
     fn create_hidden_service(&mut self,  dst_port: u16, hs_port: u16, secret_key: String) -> Result<TorHiddenService,String> {
-        let hs_key = match secret_key.len() {
-            0 => Ok(None),
-            _ => {
-                let mut decoded_buff:[u8;64] = [0;64];
-                base64::decode_config_slice(secret_key, base64::STANDARD, &mut decoded_buff)
-                    .map( |_| { Some(decoded_buff)})
+        let ports = vec![HiddenServicePort::local(hs_port, dst_port)];
+        create_hidden_service_with_mappings(this, TorHiddenServiceParam { ports, ..Default::default() }, secret_key, OwnedTorService::create_hidden_service)
+    }
+    /// options_json: {"ports": [{"hs_port":80,"target":"127.0.0.1:8080"}], "detached": bool, "max_streams": u16, "max_streams_close_circuit": bool,
+    /// "wait_ms": u64, "import_dir": String, "seed": String, "seed_label": String}, all optional but ports. Targets are socket addresses or unix socket paths ("unix:/path/to/ln.sock")
+    /// max_streams limits the streams a client can open per circuit. wait_ms only returns once enough HSDirs accepted the descriptor, the service is deleted and an error returned if that takes longer
+    /// The key is secret_key (base64 or ED25519-V3: blob, empty to generate one), or read by the SDK from the HiddenServiceDir import_dir of a standalone tor,
+    /// or derived from a wallet seed (base64) for seed_label so a restored wallet gets the same onion back.
+    /// register records the service in the SDK registry, republished on every bootstrap, the keystore must be unlocked first
+    fn create_hidden_service_with_options(&mut self, options_json: String, secret_key: String, register: bool) -> Result<TorHiddenService,String> {
+        let options: HiddenServiceOptions = serde_json::from_str(&options_json).map_err(|e| { format!("{:#?}",e)})?;
+        let sdk_key = sdk_secret_key(&options).map_err(|e| { format!("{:#?}",e)})?;
+        let wait_ms = options.wait_ms;
+        create_hidden_service_with_mappings(this, options.param, secret_key, move |owned, mut param| {
+            if let Some(sdk_key) = sdk_key {
+                if param.secret_key.is_some() {
+                    return Err(TorErrors::HiddenServiceParamError(String::from("secret_key can't be given with import_dir or seed")));
+                }
+                param.secret_key = Some(sdk_key);
             }
-        };
-
-        match hs_key {
-            Ok(key) =>{
-                this.create_hidden_service(TorHiddenServiceParam {
-                    to_port: dst_port,
-                    hs_port,
-                    secret_key: key
-                }).map_err(|e| { format! ("{:#?}",e)})
-            },
-            Err(e)=> {
-              Err(format!("{:#?}",e))
+            match (register, wait_ms) {
+                (true, Some(_)) => Err(TorErrors::HiddenServiceParamError(String::from("wait_ms can't be used to register a service"))),
+                (true, None) => owned.add_registered_hidden_service(param),
+                (false, Some(wait_ms)) => owned.create_hidden_service_and_wait(param, Duration::from_millis(wait_ms)),
+                (false, None) => owned.create_hidden_service(param),
             }
-          }
+        })
+    }
+    fn remove_registered_hidden_service(&mut self, onion: String) -> Result<(),String> {
+        this.remove_registered_hidden_service(&onion).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// JSON list of {"onion": String, "ports": [{"hs_port":u16,"target":String}], "detached": bool}
+    fn list_registered_hidden_services(&mut self) -> String {
+        serde_json::to_string(&this.list_registered_hidden_services()).unwrap()
+    }
+    /// Onion services Tor is publishing, JSON list of {"onion": String, "detached": bool, "hosted": bool,
+    /// "ports": [{"hs_port":u16,"target":String}], "registered": bool}
+    fn list_onions(&mut self) -> Result<String,String> {
+        let onions = this.list_onions().map_err(|e| { format!("{:#?}",e)})?;
+        Ok(serde_json::to_string(&onions).unwrap())
+    }
+    /// Encrypts registered onion keys at rest with a passphrase, services sealed in a previous session are published once unlocked
+    fn unlock_keystore_with_passphrase(&mut self, passphrase: String) -> Result<(),String> {
+        let wrapper = PassphraseWrapper::new(&passphrase).map_err(|e| { format!("{:#?}",e)})?;
+        this.unlock_keystore(Box::new(wrapper)).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// 32 bytes base64 wrapping key, typically kept in the Android Keystore
+    fn unlock_keystore_with_key(&mut self, wrapping_key_b64: String) -> Result<(),String> {
+        let wrapper = WrappingKey::from_base64(&wrapping_key_b64).map_err(|e| { format!("{:#?}",e)})?;
+        this.unlock_keystore(Box::new(wrapper)).map_err(|e| { format!("{:#?}",e)})
+    }
+    fn change_keystore_passphrase(&mut self, passphrase: String) -> Result<(),String> {
+        let wrapper = PassphraseWrapper::new(&passphrase).map_err(|e| { format!("{:#?}",e)})?;
+        this.change_keystore_key(Box::new(wrapper)).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// Moves a service we host to secret_key (base64 or ED25519-V3: blob, empty to generate one) keeping its ports and clients, both are served for grace_period_secs.
+    /// JSON {"onion_url":String,"migration":{"old_onion":String,"new_onion":String,"retire_at":u64,"old_signature":String,"new_signature":String}}, migration is the announcement to send to peers
+    fn rotate_hidden_service(&mut self, onion: String, secret_key: String, grace_period_secs: u64) -> Result<String,String> {
+        let new_secret_key = match secret_key.len() {
+            0 => None,
+            _ => Some(keys::from_control_blob(&secret_key).map_err(|e| { format!("{:#?}",e)})?),
+        };
+        let (hidden_service, migration) = this.rotate_hidden_service(&onion, new_secret_key, Duration::from_secs(grace_period_secs)).map_err(|e| { format!("{:#?}",e)})?;
+        Ok(serde_json::json!({ "onion_url": hidden_service.onion_url.to_string(), "migration": migration }).to_string())
+    }
+    /// Deletes the rotated services whose grace period is over, JSON array of their onions. Call it periodically
+    fn retire_rotated_services(&mut self) -> Result<String,String> {
+        let retired = this.retire_rotated_services().map_err(|e| { format!("{:#?}",e)})?;
+        Ok(serde_json::to_string(&retired).unwrap())
+    }
+    /// Only call returning an onion secret key (base64) in the clear
+    fn export_hidden_service_key(&mut self, onion: String) -> Result<String,String> {
+        this.export_hidden_service_key(&onion).map(|key| base64::encode(&key[..])).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// Explicitly exports a service we host as a HiddenServiceDir a standalone tor can serve
+    fn export_hidden_service_dir(&mut self, onion: String, dir: String) -> Result<(),String> {
+        this.export_hidden_service_dir(&onion, &dir).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// Checks if a remote onion is online, port 0 only checks its descriptor. JSON {"onion":String,"outcome":String,"descriptor_ms":u64|null,"connect_ms":u64|null,"reason":String|null}
+    /// outcome is one of DescriptorNotFound, DescriptorFound, IntroFailed, RendezvousFailed, ClientAuthFailed, PortClosed, Reachable, TimedOut, Unreachable
+    fn probe_onion(&self, onion: String, port: u16, timeout_ms: u64) -> Result<String,String> {
+        let port = if port == 0 { None } else { Some(port) };
+        let probe = this.probe_onion(&onion, port, Duration::from_millis(timeout_ms)).map_err(|e| { format!("{:#?}",e)})?;
+        Ok(serde_json::to_string(&probe).unwrap())
+    }
+    /// Checks the hosted onions in the background with a JSON {"interval_ms":u64,"timeout_ms":u64,"failures_before_republish":u32,"history_len":usize} (missing fields default),
+    /// republishing the ones failing failures_before_republish checks in a row. cb.onData gets the JSON health of a service when its status changes, see HealthMonitor.get_report
+    fn start_health_monitor(&self, param_json: String, cb: Box<dyn DataObserver>) -> Result<HealthMonitor,String> {
+        let param: HealthMonitorParam = serde_json::from_str(&param_json).map_err(|e| { format!("{:#?}",e)})?;
+        this.start_health_monitor(param, Box::new(Observer { cb })).map_err(|e| { format!("{:#?}",e)})
+    }
+    fn add_onion_client_auth(&mut self, onion: String, private_key: String, permanent: bool) -> Result<(),String> {
+        this.add_onion_client_auth(&onion, &private_key, permanent).map_err(|e| { format!("{:#?}",e)})
+    }
+    fn remove_onion_client_auth(&mut self, onion: String) -> Result<(),String> {
+        this.remove_onion_client_auth(&onion).map_err(|e| { format!("{:#?}",e)})
+    }
+    /// JSON list of {"onion": String, "client_name": String?, "permanent": bool}
+    fn list_onion_client_auth(&mut self) -> Result<String,String> {
+        this.list_onion_client_auth()
+            .map(|credentials| serde_json::to_string(&credentials).unwrap())
+            .map_err(|e| { format!("{:#?}",e)})
     }
     fn delete_hidden_service(&mut self,  onion: String) -> Result<(),String> {
          this.delete_hidden_service(onion).map_err(|e| { format! ("{:#?}",e)})
     }
-});
-
-/// TcpStream Android Interface
-foreign_class!(class TcpSocksStream {
-    self_type TcpSocksStream;
-    constructor new(target:String,socks_proxy:String,timeout_ms:u64)->Result<TcpSocksStream,String> {
-      TcpSocksStream::new_timeout(target,socks_proxy,timeout_ms).map_err(|e| { format!("{:#?}",e) })
-    }
-    fn on_data(&mut self,cb:Box<dyn DataObserver>)->Result<(),String>{
-      this.set_data_handler(Observer{
-       cb,
-      }).unwrap();
-      this.read_line_async().map_err(|e| { format!("{:#?}",e)})
+    fn resolve(&mut self, hostname: String) -> Result<String,String> {
+        this.resolve(&hostname)
+            .map(|resolution| serde_json::to_string(&resolution).unwrap())
+            .map_err(|e| { format!("{:#?}",e)})
     }
-    fn send_data(&mut self, msg:String,timeout:u64)->Result<(),String>{
-        this.send_data(msg, Some(Duration::new(timeout, 0))).map_err(|e| { format!("{:#?}",e) })
+    fn reverse_resolve(&mut self, ip: String) -> Result<String,String> {
+        let ip = ip.parse::<std::net::IpAddr>().map_err(|e| { format!("{:#?}",e)})?;
+        this.reverse_resolve(ip)
+            .map(|resolution| serde_json::to_string(&resolution).unwrap())
+            .map_err(|e| { format!("{:#?}",e)})
     }
 });
+
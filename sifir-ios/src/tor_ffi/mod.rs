@@ -1,14 +1,26 @@
 use libc::{c_char, c_void};
 use logger;
+use serde::Deserialize;
 use serde_json::json;
 use std::ffi::{CStr, CString};
+use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
 use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::time::Duration;
 use tor::{
+    dns::DnsResolution,
     hidden_service::{HiddenServiceDataHandler, HiddenServiceHandler},
+    onion::client_auth::ClientAuthKeypair,
+    onion::health::{HealthMonitor, HealthMonitorParam, HealthObserver, ServiceHealth},
+    onion::keys,
+    onion::keystore::{PassphraseWrapper, WrappingKey},
+    onion::migration::OnionMigration,
+    onion::seed,
+    onion::vanity::VanitySearch,
+    onion::OnionAddress,
     tcp_stream::{DataObserver, TcpSocksStream},
-    OwnedTorService, TorHiddenService, TorHiddenServiceParam, TorServiceParam,
+    HiddenServicePort, OwnedTorService, TorErrors, TorHiddenService, TorHiddenServiceParam,
+    TorServiceParam,
 };
 
 #[repr(C)]
@@ -49,6 +61,7 @@ pub extern "C" fn get_owned_TorService(
             socks_port: Some(socks_port),
             data_dir: dir_str,
             bootstrap_timeout_ms: Some(bootstrap_timeout_ms),
+            ..Default::default()
         };
         OwnedTorService::new(param).unwrap()
     }) {
@@ -69,6 +82,53 @@ pub extern "C" fn get_owned_TorService(
     }
 }
 #[no_mangle]
+/// Starts an OwnedTorService from a JSON encoded TorServiceParam
+/// Allows setting the optional listeners (HTTPTunnelPort, DNSPort..) not exposed by get_owned_TorService
+/// and the disk_hidden_services: [{"name": String, "ports": [{"hs_port":u16,"target":String}],
+/// "intro_dos_defense": {"rate_per_sec": u32, "burst_per_sec": u32}}], intro_dos_defense optional
+pub extern "C" fn get_owned_TorService_with_param(
+    param_json: *const c_char,
+) -> *mut BoxedResult<OwnedTorService> {
+    match catch_unwind(|| {
+        assert!(!param_json.is_null());
+        let param_str = unsafe { CStr::from_ptr(param_json) }
+            .to_str()
+            .expect("Could not get str from param_json");
+        let param: TorServiceParam =
+            serde_json::from_str(param_str).expect("Could not parse TorServiceParam json");
+        OwnedTorService::new(param).unwrap()
+    }) {
+        Ok(service) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(service)),
+            message: ResultMessage::Success,
+        })),
+        Err(e) => {
+            let message = match e.downcast::<String>() {
+                Ok(msg) => *msg,
+                Err(_) => String::from("Unknown panic"),
+            };
+            Box::into_raw(Box::new(BoxedResult {
+                result: None,
+                message: ResultMessage::Error(CString::new(message).unwrap().into_raw()),
+            }))
+        }
+    }
+}
+#[no_mangle]
+///# Safety
+/// Get the HTTPTunnelPort address of a OwnedTorService, empty if none was opened
+pub extern "C" fn get_http_tunnel_listener_of_owned_TorService(
+    owned_client: *mut OwnedTorService,
+) -> *mut c_char {
+    assert!(!owned_client.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let listener = owned
+        .http_tunnel_listener
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    CString::new(listener).unwrap().into_raw()
+}
+#[no_mangle]
 ///# Safety
 /// Get the status of a OwnedTorService
 pub extern "C" fn get_status_of_owned_TorService(
@@ -90,6 +150,142 @@ pub extern "C" fn get_status_of_owned_TorService(
 }
 #[no_mangle]
 ///# Safety
+/// Checks if a remote onion is online, fetching its descriptor then connecting to port (0 to only
+/// check the descriptor) within timeout_ms. Returns a JSON OnionProbe
+/// {"onion":String,"outcome":"DescriptorNotFound"|"DescriptorFound"|"IntroFailed"|"RendezvousFailed"|
+/// "ClientAuthFailed"|"PortClosed"|"Reachable"|"TimedOut"|"Unreachable","descriptor_ms":u64|null,
+/// "connect_ms":u64|null,"reason":String|null}
+pub extern "C" fn probe_onion(
+    owned_client: *mut OwnedTorService,
+    onion: *const c_char,
+    port: u16,
+    timeout_ms: u64,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    assert!(!onion.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    let port = if port == 0 { None } else { Some(port) };
+    string_result(
+        owned
+            .probe_onion(onion_str, port, Duration::from_millis(timeout_ms))
+            .map(|probe| serde_json::to_string(&probe).unwrap()),
+    )
+}
+#[no_mangle]
+///# Safety
+/// Starts checking the hosted onions in the background with a JSON HealthMonitorParam
+/// {"interval_ms":u64,"timeout_ms":u64,"failures_before_republish":u32,"history_len":usize}
+/// (missing fields default). observer.on_success is called with a JSON ServiceHealth
+/// {"onion":String,"status":"Unknown"|"Reachable"|"Unreachable"|"Republished",
+/// "consecutive_failures":u32,"republished":u32,"availability":f64,
+/// "history":[{"at_ms":u64,"outcome":String,"latency_ms":u64|null}]} when a status changes
+pub extern "C" fn start_health_monitor(
+    owned_client: *mut OwnedTorService,
+    param_json: *const c_char,
+    observer: Observer,
+) -> *mut BoxedResult<HealthMonitor> {
+    assert!(!owned_client.is_null());
+    assert!(!param_json.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let param_str = unsafe { CStr::from_ptr(param_json) }
+        .to_str()
+        .expect("Could not obtain str from param_json");
+    let started = serde_json::from_str::<HealthMonitorParam>(param_str)
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|param| {
+            owned
+                .start_health_monitor(param, Box::new(observer))
+                .map_err(|e| format!("{:?}", e))
+        });
+    match started {
+        Ok(monitor) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(monitor)),
+            message: ResultMessage::Success,
+        })),
+        Err(message) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(message).unwrap().into_raw()),
+        })),
+    }
+}
+#[no_mangle]
+///# Safety
+/// JSON array of the ServiceHealth checked so far, see start_health_monitor
+pub extern "C" fn health_monitor_report(
+    monitor: *mut HealthMonitor,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!monitor.is_null());
+    let monitor = unsafe { &mut *monitor };
+    Box::into_raw(Box::new(BoxedResult {
+        result: Some(Box::new(
+            CString::new(serde_json::to_string(&monitor.report()).unwrap())
+                .unwrap()
+                .into_raw(),
+        )),
+        message: ResultMessage::Success,
+    }))
+}
+#[no_mangle]
+///# Safety
+/// Resolves a hostname through Tor returning a JSON DnsResolution
+pub extern "C" fn resolve_hostname(
+    owned_client: *mut OwnedTorService,
+    hostname: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    assert!(!hostname.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let hostname_str = unsafe { CStr::from_ptr(hostname) }
+        .to_str()
+        .expect("Could not get str from hostname");
+    dns_resolution_result(owned.resolve(hostname_str))
+}
+#[no_mangle]
+///# Safety
+/// Reverse resolves an IP through Tor returning a JSON DnsResolution
+pub extern "C" fn reverse_resolve_ip(
+    owned_client: *mut OwnedTorService,
+    ip: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    assert!(!ip.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let ip_str = unsafe { CStr::from_ptr(ip) }
+        .to_str()
+        .expect("Could not get str from ip");
+    match ip_str.parse::<IpAddr>() {
+        Ok(ip) => dns_resolution_result(owned.reverse_resolve(ip)),
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(format!("{}", e)).unwrap().into_raw()),
+        })),
+    }
+}
+fn dns_resolution_result(
+    resolution: Result<DnsResolution, TorErrors>,
+) -> *mut BoxedResult<*mut c_char> {
+    match resolution {
+        Ok(resolution) => {
+            let json_payload = serde_json::to_string(&resolution).unwrap();
+            Box::into_raw(Box::new(BoxedResult {
+                result: Some(Box::new(CString::new(json_payload).unwrap().into_raw())),
+                message: ResultMessage::Success,
+            }))
+        }
+        Err(e) => {
+            let message = format!("{:#?}", e);
+            Box::into_raw(Box::new(BoxedResult {
+                result: None,
+                message: ResultMessage::Error(CString::new(message).unwrap().into_raw()),
+            }))
+        }
+    }
+}
+#[no_mangle]
+///# Safety
 /// Start a proxied TcpStream
 pub extern "C" fn tcp_stream_start(
     target: *const c_char,
@@ -109,7 +305,7 @@ pub extern "C" fn tcp_stream_start(
             .expect("Could not get str from target")
             .into();
 
-        TcpSocksStream::new_timeout(target_str, proxy_str, timeout_ms).unwrap()
+        TcpSocksStream::new_timeout(target_str, proxy_str, timeout_ms, false).unwrap()
     }) {
         Ok(stream) => Box::into_raw(Box::new(BoxedResult {
             result: Some(Box::new(stream)),
@@ -127,6 +323,33 @@ pub extern "C" fn tcp_stream_start(
         }
     }
 }
+#[no_mangle]
+///# Safety
+/// Start a TcpStream through the SocksPort of owned_client, non onion targets are rejected if it
+/// runs in onion only mode
+pub extern "C" fn tor_service_tcp_stream_start(
+    owned_client: *mut OwnedTorService,
+    target: *const c_char,
+    timeout_ms: u64,
+) -> *mut BoxedResult<TcpSocksStream> {
+    assert!(!owned_client.is_null());
+    assert!(!target.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let target_str: String = unsafe { CStr::from_ptr(target) }
+        .to_str()
+        .expect("Could not get str from target")
+        .into();
+    match owned.tcp_stream(target_str, timeout_ms) {
+        Ok(stream) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(stream)),
+            message: ResultMessage::Success,
+        })),
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(format!("{:?}", e)).unwrap().into_raw()),
+        })),
+    }
+}
 
 #[repr(C)]
 pub struct Observer {
@@ -146,6 +369,12 @@ impl DataObserver for Observer {
         (self.on_err)(CString::new(data).unwrap().into_raw(), self.context);
     }
 }
+impl HealthObserver for Observer {
+    fn on_health_change(&self, health: &ServiceHealth) {
+        let data = serde_json::to_string(health).unwrap();
+        (self.on_success)(CString::new(data).unwrap().into_raw(), self.context);
+    }
+}
 #[no_mangle]
 ///# Safety
 /// Send a Message over a tcpStream
@@ -185,7 +414,7 @@ pub extern "C" fn tcp_stream_send_msg(
         let mut stream = unsafe { &mut *stream_ptr };
         let msg_str: String = unsafe { CStr::from_ptr(msg) }
             .to_str()
-            .expect("Could not get str from proxy")
+            .expect("Could not get str from msg")
             .into();
         stream.send_data(msg_str, Some(Duration::new(timeout, 0)))
     } {
@@ -209,29 +438,419 @@ pub extern "C" fn create_hidden_service(
 ) -> *mut BoxedResult<*mut c_char> {
     assert!(!owned_client.is_null());
     let owned = unsafe { &mut *owned_client };
+    let param = TorHiddenServiceParam {
+        ports: vec![HiddenServicePort::local(hs_port, dst_port)],
+        ..Default::default()
+    };
+    hidden_service_result(
+        owned,
+        param,
+        secret_key,
+        OwnedTorService::create_hidden_service,
+    )
+}
+#[no_mangle]
+///# Safety
+/// Creates a Hidden service from a JSON object of options, all optional but ports:
+/// {"ports": [{"hs_port":80,"target":"127.0.0.1:8080"}], "detached": bool, "max_streams": u16,
+/// "max_streams_close_circuit": bool, "wait_ms": u64, "import_dir": String, "seed": String,
+/// "seed_label": String}
+/// Targets are socket addresses or unix socket paths ("unix:/path/to/ln.sock").
+/// max_streams limits the streams a client can open per circuit, protecting the phone from floods
+/// wait_ms only returns once enough HSDirs accepted the descriptor, that is once clients can
+/// reach the service, which is deleted and an error returned if that takes longer
+/// The key is secret_key (base64 or ED25519-V3: blob, null or empty to generate one), or read by
+/// the SDK without crossing FFI: from the HiddenServiceDir import_dir of a standalone tor, or
+/// derived from a wallet seed (base64) for seed_label so a restored wallet gets the same onion back
+/// register records the service in the SDK registry, republished on every bootstrap, the keystore
+/// must be unlocked first
+pub extern "C" fn create_hidden_service_with_options(
+    owned_client: *mut OwnedTorService,
+    options_json: *const c_char,
+    secret_key: *const c_char,
+    register: bool,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    assert!(!options_json.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let options_str = unsafe { CStr::from_ptr(options_json) }
+        .to_str()
+        .expect("Could not get str from options_json");
+    let options = serde_json::from_str::<HiddenServiceOptions>(options_str)
+        .map_err(|e| format!("{}", e))
+        .and_then(|options| {
+            sdk_secret_key(&options)
+                .map(|sdk_key| (options, sdk_key))
+                .map_err(|e| format!("{:?}", e))
+        });
+    match options {
+        Ok((options, sdk_key)) => {
+            let wait_ms = options.wait_ms;
+            hidden_service_result(owned, options.param, secret_key, move |owned, mut param| {
+                if let Some(sdk_key) = sdk_key {
+                    if param.secret_key.is_some() {
+                        return Err(TorErrors::HiddenServiceParamError(String::from(
+                            "secret_key can't be given with import_dir or seed",
+                        )));
+                    }
+                    param.secret_key = Some(sdk_key);
+                }
+                match (register, wait_ms) {
+                    (true, Some(_)) => Err(TorErrors::HiddenServiceParamError(String::from(
+                        "wait_ms can't be used to register a service",
+                    ))),
+                    (true, None) => owned.add_registered_hidden_service(param),
+                    (false, Some(wait_ms)) => {
+                        owned.create_hidden_service_and_wait(param, Duration::from_millis(wait_ms))
+                    }
+                    (false, None) => owned.create_hidden_service(param),
+                }
+            })
+        }
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(e).unwrap().into_raw()),
+        })),
+    }
+}
+
+/// options_json of create_hidden_service_with_options: a TorHiddenServiceParam and how to create it
+#[derive(Deserialize)]
+struct HiddenServiceOptions {
+    #[serde(flatten)]
+    param: TorHiddenServiceParam,
+    wait_ms: Option<u64>,
+    import_dir: Option<String>,
+    seed: Option<String>,
+    seed_label: Option<String>,
+}
+
+/// Key the SDK reads or derives itself for options, None to use secret_key
+fn sdk_secret_key(options: &HiddenServiceOptions) -> Result<Option<[u8; 64]>, TorErrors> {
+    match (&options.import_dir, &options.seed) {
+        (Some(_), Some(_)) => Err(TorErrors::HiddenServiceParamError(String::from(
+            "import_dir and seed can't be used together",
+        ))),
+        (Some(dir), None) => keys::read_hidden_service_dir(dir).map(Some),
+        (None, Some(seed)) => {
+            let label = options.seed_label.as_deref().ok_or_else(|| {
+                TorErrors::HiddenServiceParamError(String::from("seed needs a seed_label"))
+            })?;
+            derive_seed_key(seed, label).map(Some)
+        }
+        (None, None) => Ok(None),
+    }
+}
+#[no_mangle]
+///# Safety
+/// Lists the onion services Tor is publishing as a JSON list of
+/// {"onion": String, "detached": bool, "hosted": bool, "ports": [{"hs_port":u16,"target":String}],
+/// "registered": bool}
+/// ports are only known for services hosted by this OwnedTorService
+pub extern "C" fn list_onions(owned_client: *mut OwnedTorService) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    let owned = unsafe { &mut *owned_client };
+    match owned.list_onions() {
+        Ok(onions) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(
+                CString::new(serde_json::to_string(&onions).unwrap())
+                    .unwrap()
+                    .into_raw(),
+            )),
+            message: ResultMessage::Success,
+        })),
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(format!("{:?}", e)).unwrap().into_raw()),
+        })),
+    }
+}
+#[no_mangle]
+///# Safety
+/// Onion of a service from the disk_hidden_services of the TorServiceParam, by name
+pub extern "C" fn disk_hidden_service(
+    owned_client: *mut OwnedTorService,
+    name: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    assert!(!name.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let name_str = unsafe { CStr::from_ptr(name) }
+        .to_str()
+        .expect("Could not get str from name");
+    string_result(
+        owned
+            .disk_hidden_service(name_str)
+            .map(|onion| onion.to_string()),
+    )
+}
+#[no_mangle]
+///# Safety
+/// Unpublishes a registered Hidden service and removes it from the registry
+pub extern "C" fn remove_registered_hidden_service(
+    owned_client: *mut OwnedTorService,
+    onion: *const c_char,
+) -> *mut ResultMessage {
+    assert!(!owned_client.is_null());
+    assert!(!onion.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    match owned.remove_registered_hidden_service(onion_str) {
+        Ok(_) => Box::into_raw(Box::new(ResultMessage::Success)),
+        Err(e) => {
+            let message = format!("{:?}", e);
+            Box::into_raw(Box::new(ResultMessage::Error(
+                CString::new(message).unwrap().into_raw(),
+            )))
+        }
+    }
+}
+#[no_mangle]
+///# Safety
+/// Lists the registered Hidden services as a JSON list of
+/// {"onion": String, "ports": [{"hs_port":u16,"target":String}], "detached": bool}
+pub extern "C" fn list_registered_hidden_services(
+    owned_client: *mut OwnedTorService,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let services = owned.list_registered_hidden_services();
+    Box::into_raw(Box::new(BoxedResult {
+        result: Some(Box::new(
+            CString::new(serde_json::to_string(&services).unwrap())
+                .unwrap()
+                .into_raw(),
+        )),
+        message: ResultMessage::Success,
+    }))
+}
+#[no_mangle]
+///# Safety
+/// Unlocks the keystore encrypting registered onion keys at rest with a passphrase (Argon2id)
+/// Registered services sealed in a previous session are published once it is unlocked
+pub extern "C" fn unlock_keystore_with_passphrase(
+    owned_client: *mut OwnedTorService,
+    passphrase: *const c_char,
+) -> *mut ResultMessage {
+    assert!(!owned_client.is_null());
+    assert!(!passphrase.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let passphrase_str = unsafe { CStr::from_ptr(passphrase) }
+        .to_str()
+        .expect("Could not obtain str from passphrase");
+    let unlocked = PassphraseWrapper::new(passphrase_str)
+        .and_then(|wrapper| owned.unlock_keystore(Box::new(wrapper)));
+    message_result(unlocked)
+}
+#[no_mangle]
+///# Safety
+/// Unlocks the keystore with a 32 bytes base64 wrapping key, typically kept in the Keychain
+pub extern "C" fn unlock_keystore_with_key(
+    owned_client: *mut OwnedTorService,
+    wrapping_key_b64: *const c_char,
+) -> *mut ResultMessage {
+    assert!(!owned_client.is_null());
+    assert!(!wrapping_key_b64.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let key_str = unsafe { CStr::from_ptr(wrapping_key_b64) }
+        .to_str()
+        .expect("Could not obtain str from wrapping key");
+    let unlocked = WrappingKey::from_base64(key_str)
+        .and_then(|wrapper| owned.unlock_keystore(Box::new(wrapper)));
+    message_result(unlocked)
+}
+#[no_mangle]
+///# Safety
+/// Encrypts the registered onion keys with a new passphrase, the keystore must be unlocked
+pub extern "C" fn change_keystore_passphrase(
+    owned_client: *mut OwnedTorService,
+    passphrase: *const c_char,
+) -> *mut ResultMessage {
+    assert!(!owned_client.is_null());
+    assert!(!passphrase.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let passphrase_str = unsafe { CStr::from_ptr(passphrase) }
+        .to_str()
+        .expect("Could not obtain str from passphrase");
+    let changed = PassphraseWrapper::new(passphrase_str)
+        .and_then(|wrapper| owned.change_keystore_key(Box::new(wrapper)));
+    message_result(changed)
+}
+#[no_mangle]
+///# Safety
+/// Moves a Hidden service we host to secret_key (base64 or ED25519-V3: blob, null or empty to
+/// generate one), keeping its ports and clients, and serves both for grace_period_secs.
+/// Returns JSON {"onion_url":String,"migration":OnionMigration} where OnionMigration is
+/// {"old_onion":String,"new_onion":String,"retire_at":u64,"old_signature":String,
+/// "new_signature":String}, the announcement to send to peers
+pub extern "C" fn rotate_hidden_service(
+    owned_client: *mut OwnedTorService,
+    onion: *const c_char,
+    secret_key: *const c_char,
+    grace_period_secs: u64,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    assert!(!onion.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    let secret_key_str = match secret_key.is_null() {
+        true => "",
+        false => unsafe { CStr::from_ptr(secret_key) }
+            .to_str()
+            .expect("Could not obtain str from secret_key"),
+    };
+    let new_secret_key = match secret_key_str.len() {
+        0 => Ok(None),
+        _ => keys::from_control_blob(secret_key_str).map(Some),
+    };
+    string_result(new_secret_key.and_then(|new_secret_key| {
+        let (hidden_service, migration) = owned.rotate_hidden_service(
+            onion_str,
+            new_secret_key,
+            Duration::from_secs(grace_period_secs),
+        )?;
+        Ok(json!({
+            "onion_url": hidden_service.onion_url.to_string(),
+            "migration": migration,
+        })
+        .to_string())
+    }))
+}
+#[no_mangle]
+///# Safety
+/// Deletes the rotated Hidden services whose grace period is over, returns a JSON array of their
+/// onions. Call it periodically
+pub extern "C" fn retire_rotated_services(
+    owned_client: *mut OwnedTorService,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    let owned = unsafe { &mut *owned_client };
+    string_result(
+        owned
+            .retire_rotated_services()
+            .map(|retired| serde_json::to_string(&retired).unwrap()),
+    )
+}
+#[no_mangle]
+///# Safety
+/// Explicitly exports the base64 secret key of a Hidden service we host
+/// This is the only call returning an onion secret key in the clear
+pub extern "C" fn export_hidden_service_key(
+    owned_client: *mut OwnedTorService,
+    onion: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    assert!(!onion.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    match owned.export_hidden_service_key(onion_str) {
+        Ok(secret_key) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(
+                CString::new(base64::encode(&secret_key[..]))
+                    .unwrap()
+                    .into_raw(),
+            )),
+            message: ResultMessage::Success,
+        })),
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(format!("{:?}", e)).unwrap().into_raw()),
+        })),
+    }
+}
+#[no_mangle]
+///# Safety
+/// Explicitly exports a Hidden service we host to dir as a HiddenServiceDir
+/// (hs_ed25519_secret_key, hs_ed25519_public_key and hostname) a standalone tor can serve
+pub extern "C" fn export_hidden_service_dir(
+    owned_client: *mut OwnedTorService,
+    onion: *const c_char,
+    dir: *const c_char,
+) -> *mut ResultMessage {
+    assert!(!owned_client.is_null());
+    assert!(!onion.is_null());
+    assert!(!dir.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    let dir_str = unsafe { CStr::from_ptr(dir) }
+        .to_str()
+        .expect("Could not obtain str from dir");
+    message_result(owned.export_hidden_service_dir(onion_str, dir_str))
+}
+#[no_mangle]
+///# Safety
+/// Returns the onion address create_hidden_service_with_options creates for seed and label,
+/// without starting it
+pub extern "C" fn derive_onion_address(
+    seed: *const c_char,
+    label: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!seed.is_null());
+    assert!(!label.is_null());
+    let seed_str = unsafe { CStr::from_ptr(seed) }
+        .to_str()
+        .expect("Could not obtain str from seed");
+    let label_str = unsafe { CStr::from_ptr(label) }
+        .to_str()
+        .expect("Could not obtain str from label");
+    string_result(
+        derive_seed_key(seed_str, label_str)
+            .map(|secret_key| String::from(keys::to_hostname(&secret_key).trim())),
+    )
+}
+
+fn derive_seed_key(seed: &str, label: &str) -> Result<[u8; 64], TorErrors> {
+    let seed_bytes = decode_base64("seed", seed)?;
+    seed::derive_secret_key(&seed_bytes, label)
+}
+
+fn message_result(result: Result<(), TorErrors>) -> *mut ResultMessage {
+    match result {
+        Ok(_) => Box::into_raw(Box::new(ResultMessage::Success)),
+        Err(e) => {
+            let message = format!("{:?}", e);
+            Box::into_raw(Box::new(ResultMessage::Error(
+                CString::new(message).unwrap().into_raw(),
+            )))
+        }
+    }
+}
+
+fn hidden_service_result<F>(
+    owned: &mut OwnedTorService,
+    mut param: TorHiddenServiceParam,
+    secret_key: *const c_char,
+    create: F,
+) -> *mut BoxedResult<*mut c_char>
+where
+    F: FnOnce(&mut OwnedTorService, TorHiddenServiceParam) -> Result<TorHiddenService, TorErrors>,
+{
     let hs_key = match secret_key.is_null() {
         true => None,
         false => {
             let secret_key_str = unsafe { CStr::from_ptr(secret_key) }
                 .to_str()
-                .expect("Could not get str from proxy");
+                .expect("Could not get str from secret_key");
 
             if secret_key_str.len() < 1 {
                 None
             } else {
-                // Return on base64 parse error
-                let mut decoded_buff: [u8; 64] = [0; 64];
-                match base64::decode_config_slice(
-                    secret_key_str,
-                    base64::STANDARD,
-                    &mut decoded_buff,
-                ) {
-                    Ok(_) => Some(decoded_buff),
+                // base64 of the key or an ED25519-V3: blob as used by the control port
+                match keys::from_control_blob(secret_key_str) {
+                    Ok(decoded_key) => Some(decoded_key),
                     Err(e) => {
                         return Box::into_raw(Box::new(BoxedResult {
                             result: None,
                             message: ResultMessage::Error(
-                                CString::new(format!("{}", e)).unwrap().into_raw(),
+                                CString::new(format!("{:?}", e)).unwrap().into_raw(),
                             ),
                         }))
                     }
@@ -240,17 +859,12 @@ pub extern "C" fn create_hidden_service(
         }
     };
 
-    let hidden_service = owned.create_hidden_service(TorHiddenServiceParam {
-        to_port: dst_port,
-        hs_port,
-        secret_key: hs_key.into(),
-    });
+    param.secret_key = hs_key;
+    let hidden_service = create(owned, param);
     match hidden_service {
-        Ok(TorHiddenService {
-            onion_url,
-            secret_key,
-        }) => {
-            let json_payload = json!({ "onion_url": onion_url.to_string(), "secret_key": base64::encode(secret_key) });
+        // The secret key only leaves the SDK through export_hidden_service_key
+        Ok(TorHiddenService { onion_url, .. }) => {
+            let json_payload = json!({ "onion_url": onion_url.to_string() });
             Box::into_raw(Box::new(BoxedResult {
                 result: Some(Box::new(
                     CString::new(json_payload.to_string()).unwrap().into_raw(),
@@ -293,6 +907,331 @@ pub extern "C" fn delete_hidden_service(
 }
 #[no_mangle]
 ///# Safety
+/// Adds the x25519 private key (base32 or base64) used to connect to an onion requiring client
+/// authorization, permanent credentials are kept across restarts
+pub extern "C" fn add_onion_client_auth(
+    owned_client: *mut OwnedTorService,
+    onion: *const c_char,
+    private_key: *const c_char,
+    permanent: bool,
+) -> *mut ResultMessage {
+    assert!(!owned_client.is_null());
+    assert!(!onion.is_null());
+    assert!(!private_key.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    let key_str = unsafe { CStr::from_ptr(private_key) }
+        .to_str()
+        .expect("Could not obtain str from private key");
+    match owned.add_onion_client_auth(onion_str, key_str, permanent) {
+        Ok(_) => Box::into_raw(Box::new(ResultMessage::Success)),
+        Err(e) => {
+            let message = format!("{:?}", e);
+            Box::into_raw(Box::new(ResultMessage::Error(
+                CString::new(message).unwrap().into_raw(),
+            )))
+        }
+    }
+}
+#[no_mangle]
+///# Safety
+/// Removes the client credentials of an onion
+pub extern "C" fn remove_onion_client_auth(
+    owned_client: *mut OwnedTorService,
+    onion: *const c_char,
+) -> *mut ResultMessage {
+    assert!(!owned_client.is_null());
+    assert!(!onion.is_null());
+    let owned = unsafe { &mut *owned_client };
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    match owned.remove_onion_client_auth(onion_str) {
+        Ok(_) => Box::into_raw(Box::new(ResultMessage::Success)),
+        Err(e) => {
+            let message = format!("{:?}", e);
+            Box::into_raw(Box::new(ResultMessage::Error(
+                CString::new(message).unwrap().into_raw(),
+            )))
+        }
+    }
+}
+#[no_mangle]
+///# Safety
+/// Lists the onions we hold client credentials for as a JSON list of
+/// {"onion": String, "client_name": String?, "permanent": bool}
+pub extern "C" fn list_onion_client_auth(
+    owned_client: *mut OwnedTorService,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!owned_client.is_null());
+    let owned = unsafe { &mut *owned_client };
+    match owned.list_onion_client_auth() {
+        Ok(credentials) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(
+                CString::new(serde_json::to_string(&credentials).unwrap())
+                    .unwrap()
+                    .into_raw(),
+            )),
+            message: ResultMessage::Success,
+        })),
+        Err(e) => {
+            let message = format!("{:#?}", e);
+            Box::into_raw(Box::new(BoxedResult {
+                result: None,
+                message: ResultMessage::Error(CString::new(message).unwrap().into_raw()),
+            }))
+        }
+    }
+}
+#[no_mangle]
+///# Safety
+/// Generates a client authorization x25519 keypair returning it as JSON:
+/// {"public_key": base32, "private_key": base32}
+/// The public key goes to the service, the private key to the client
+pub extern "C" fn generate_client_auth_keypair() -> *mut BoxedResult<*mut c_char> {
+    let keypair = ClientAuthKeypair::generate();
+    Box::into_raw(Box::new(BoxedResult {
+        result: Some(Box::new(
+            CString::new(serde_json::to_string(&keypair).unwrap())
+                .unwrap()
+                .into_raw(),
+        )),
+        message: ResultMessage::Success,
+    }))
+}
+#[no_mangle]
+///# Safety
+/// Starts searching in the background for an onion key whose address starts with prefix
+/// (a-z, 2-7), on threads workers or all cores if 0
+pub extern "C" fn start_vanity_search(
+    prefix: *const c_char,
+    threads: u32,
+) -> *mut BoxedResult<VanitySearch> {
+    assert!(!prefix.is_null());
+    let prefix_str = unsafe { CStr::from_ptr(prefix) }
+        .to_str()
+        .expect("Could not obtain str from prefix");
+    let threads = if threads == 0 {
+        None
+    } else {
+        Some(threads as usize)
+    };
+    match VanitySearch::start(prefix_str, threads) {
+        Ok(search) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(search)),
+            message: ResultMessage::Success,
+        })),
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(format!("{:?}", e)).unwrap().into_raw()),
+        })),
+    }
+}
+#[no_mangle]
+///# Safety
+/// Progress of a vanity search as JSON:
+/// {"attempts":u64,"elapsed_ms":u64,"keys_per_sec":u64,"expected_attempts":f64,"eta_ms":u64|null,
+/// "found":bool,"cancelled":bool}
+pub extern "C" fn vanity_search_progress(
+    search: *mut VanitySearch,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!search.is_null());
+    let search = unsafe { &mut *search };
+    Box::into_raw(Box::new(BoxedResult {
+        result: Some(Box::new(
+            CString::new(serde_json::to_string(&search.progress()).unwrap())
+                .unwrap()
+                .into_raw(),
+        )),
+        message: ResultMessage::Success,
+    }))
+}
+#[no_mangle]
+///# Safety
+/// Stops a vanity search, vanity_search_wait then returns an error
+pub extern "C" fn cancel_vanity_search(search: *mut VanitySearch) {
+    assert!(!search.is_null());
+    let search = unsafe { &mut *search };
+    search.cancel();
+}
+#[no_mangle]
+///# Safety
+/// Blocks until the search finds a key, returned as JSON {"onion":String,"secret_key":String}
+/// This explicitly exports the base64 secret key, pass it as secret_key to create the service
+pub extern "C" fn vanity_search_wait(search: *mut VanitySearch) -> *mut BoxedResult<*mut c_char> {
+    assert!(!search.is_null());
+    let search = unsafe { &mut *search };
+    match search.wait() {
+        Ok(key) => {
+            let json_payload =
+                json!({ "onion": key.onion, "secret_key": base64::encode(&key.secret_key[..]) });
+            Box::into_raw(Box::new(BoxedResult {
+                result: Some(Box::new(
+                    CString::new(json_payload.to_string()).unwrap().into_raw(),
+                )),
+                message: ResultMessage::Success,
+            }))
+        }
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(format!("{:?}", e)).unwrap().into_raw()),
+        })),
+    }
+}
+#[no_mangle]
+///# Safety
+/// Generates an onion key without a running daemon, returned as JSON
+/// {"onion":String,"secret_key":String}
+/// This explicitly exports the base64 secret key, pass it as secret_key to create the service
+pub extern "C" fn generate_onion_key() -> *mut BoxedResult<*mut c_char> {
+    let secret_key = keys::generate_secret_key();
+    string_result(keys::onion_address(&secret_key).map(|onion| {
+        json!({ "onion": onion.to_string(), "secret_key": base64::encode(&secret_key[..]) })
+            .to_string()
+    }))
+}
+#[no_mangle]
+///# Safety
+/// Onion address of a secret key given as base64 or as an ED25519-V3: blob
+pub extern "C" fn onion_address_from_secret_key(
+    secret_key: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!secret_key.is_null());
+    let secret_key_str = unsafe { CStr::from_ptr(secret_key) }
+        .to_str()
+        .expect("Could not obtain str from secret_key");
+    string_result(
+        keys::from_control_blob(secret_key_str)
+            .and_then(|secret_key| keys::onion_address(&secret_key))
+            .map(|onion| onion.to_string()),
+    )
+}
+#[no_mangle]
+///# Safety
+/// Checks onion is a valid v3 address (with or without port), the error says what is wrong
+pub extern "C" fn validate_onion_address(onion: *const c_char) -> *mut ResultMessage {
+    assert!(!onion.is_null());
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    message_result(onion_str.parse::<OnionAddress>().map(|_| ()))
+}
+#[no_mangle]
+///# Safety
+/// Signs the base64 message with an onion secret key (base64 or ED25519-V3: blob), returns the
+/// base64 ed25519 signature anyone can check against the onion address
+pub extern "C" fn sign_with_onion_key(
+    secret_key: *const c_char,
+    message: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!secret_key.is_null());
+    assert!(!message.is_null());
+    let secret_key_str = unsafe { CStr::from_ptr(secret_key) }
+        .to_str()
+        .expect("Could not obtain str from secret_key");
+    let message_str = unsafe { CStr::from_ptr(message) }
+        .to_str()
+        .expect("Could not obtain str from message");
+    string_result(
+        keys::from_control_blob(secret_key_str).and_then(|secret_key| {
+            let message = decode_base64("message", message_str)?;
+            keys::sign(&secret_key, &message).map(|signature| base64::encode(&signature[..]))
+        }),
+    )
+}
+#[no_mangle]
+///# Safety
+/// Checks a base64 signature of the base64 message was made with the key of onion, Success only
+/// if it is valid
+pub extern "C" fn verify_onion_signature(
+    onion: *const c_char,
+    message: *const c_char,
+    signature: *const c_char,
+) -> *mut ResultMessage {
+    assert!(!onion.is_null());
+    assert!(!message.is_null());
+    assert!(!signature.is_null());
+    let onion_str = unsafe { CStr::from_ptr(onion) }
+        .to_str()
+        .expect("Could not obtain str from onion");
+    let message_str = unsafe { CStr::from_ptr(message) }
+        .to_str()
+        .expect("Could not obtain str from message");
+    let signature_str = unsafe { CStr::from_ptr(signature) }
+        .to_str()
+        .expect("Could not obtain str from signature");
+    message_result(onion_str.parse::<OnionAddress>().and_then(|onion| {
+        let message = decode_base64("message", message_str)?;
+        let signature = decode_base64("signature", signature_str)?;
+        if onion.verify(&message, &signature) {
+            Ok(())
+        } else {
+            Err(TorErrors::OnionKeyError(String::from("Invalid signature")))
+        }
+    }))
+}
+
+#[no_mangle]
+///# Safety
+/// Checks both signatures of a JSON OnionMigration received from a peer, see
+/// rotate_hidden_service. Success only if it is valid
+pub extern "C" fn verify_onion_migration(migration_json: *const c_char) -> *mut ResultMessage {
+    assert!(!migration_json.is_null());
+    let migration_str = unsafe { CStr::from_ptr(migration_json) }
+        .to_str()
+        .expect("Could not obtain str from migration_json");
+    message_result(parse_migration(migration_str).and_then(|migration| migration.verify()))
+}
+#[no_mangle]
+///# Safety
+/// Verifies a JSON OnionMigration and returns the address to keep for stored: the new onion
+/// (with the port of stored) if stored is the old one, stored unchanged otherwise
+pub extern "C" fn migrate_onion_address(
+    migration_json: *const c_char,
+    stored: *const c_char,
+) -> *mut BoxedResult<*mut c_char> {
+    assert!(!migration_json.is_null());
+    assert!(!stored.is_null());
+    let migration_str = unsafe { CStr::from_ptr(migration_json) }
+        .to_str()
+        .expect("Could not obtain str from migration_json");
+    let stored_str = unsafe { CStr::from_ptr(stored) }
+        .to_str()
+        .expect("Could not obtain str from stored");
+    string_result(parse_migration(migration_str).and_then(|migration| {
+        Ok(match migration.migrate(stored_str)? {
+            Some(onion) => onion.to_string(),
+            None => String::from(stored_str),
+        })
+    }))
+}
+
+fn parse_migration(migration_json: &str) -> Result<OnionMigration, TorErrors> {
+    serde_json::from_str(migration_json)
+        .map_err(|e| TorErrors::OnionMigrationError(format!("Invalid migration: {}", e)))
+}
+
+fn decode_base64(name: &str, encoded: &str) -> Result<Vec<u8>, TorErrors> {
+    base64::decode(encoded.trim())
+        .map_err(|e| TorErrors::OnionKeyError(format!("{} is not base64: {}", name, e)))
+}
+
+fn string_result(result: Result<String, TorErrors>) -> *mut BoxedResult<*mut c_char> {
+    match result {
+        Ok(value) => Box::into_raw(Box::new(BoxedResult {
+            result: Some(Box::new(CString::new(value).unwrap().into_raw())),
+            message: ResultMessage::Success,
+        })),
+        Err(e) => Box::into_raw(Box::new(BoxedResult {
+            result: None,
+            message: ResultMessage::Error(CString::new(format!("{:?}", e)).unwrap().into_raw()),
+        })),
+    }
+}
+#[no_mangle]
+///# Safety
 /// Starts an HTTP request server on dst_port calling the observer with data
 pub extern "C" fn start_http_hidden_service_handler(
     dst_port: u16,
@@ -352,6 +1291,20 @@ pub unsafe extern "C" fn shutdown_owned_TorService(owned_client: *mut OwnedTorSe
 }
 #[no_mangle]
 ///# Safety
+/// Destroy a vanity search, cancelling it if still running
+pub unsafe extern "C" fn destroy_vanity_search(search: *mut VanitySearch) {
+    assert!(!search.is_null());
+    let _: Box<VanitySearch> = Box::from_raw(search);
+}
+#[no_mangle]
+///# Safety
+/// Destroy a health monitor, stopping it
+pub unsafe extern "C" fn destroy_health_monitor(monitor: *mut HealthMonitor) {
+    assert!(!monitor.is_null());
+    let _: Box<HealthMonitor> = Box::from_raw(monitor);
+}
+#[no_mangle]
+///# Safety
 /// Destroy and release HiddenServiceHandler
 pub unsafe extern "C" fn destroy_hidden_service_handler(hs_handler: *mut HiddenServiceHandler) {
     assert!(!hs_handler.is_null());
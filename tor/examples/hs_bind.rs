@@ -1,4 +1,5 @@
 use std::convert::TryInto;
+use tor::onion::keystore::PassphraseWrapper;
 use tor::*;
 
 fn main() {
@@ -12,21 +13,39 @@ fn main() {
         socks_port: Some(socks_port),
         data_dir: String::from("/tmp/sifir_rs_sdk/"),
         bootstrap_timeout_ms: Some(45000),
+        ..Default::default()
     }
     .try_into()
     .unwrap();
     println!("---------Starting Tor Daemon and Socks Port ------");
     let mut owned_node = service.into_owned_node().unwrap();
+    // Registered keys are sealed at rest, services registered in a previous run are published
+    // again once the keystore is unlocked with the passphrase they were sealed with
+    loop {
+        println!("Enter the passphrase protecting your onion keys:");
+        let mut passphrase = String::new();
+        std::io::stdin().read_line(&mut passphrase).unwrap();
+        let wrapper = PassphraseWrapper::new(passphrase.trim_end_matches(&['\r', '\n'][..]));
+        match wrapper.and_then(|wrapper| owned_node.unlock_keystore(Box::new(wrapper))) {
+            Ok(_) => break,
+            Err(e) => println!("Could not unlock the keystore: {:?}", e),
+        }
+    }
+    for registered in owned_node.list_registered_hidden_services() {
+        println!(
+            "Restored Hidden Service {} forwarding {:?}",
+            registered.onion, registered.ports
+        );
+    }
     loop {
         println!("Enter a port to foward onion:");
         let mut port = String::new();
         std::io::stdin().read_line(&mut port).unwrap();
         let to_port: u16 = port.trim().parse::<u16>().unwrap();
         let service_key = owned_node
-            .create_hidden_service(TorHiddenServiceParam {
-                to_port,
-                hs_port,
-                secret_key: None,
+            .add_registered_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(hs_port, to_port)],
+                ..Default::default()
             })
             .unwrap();
 
@@ -34,12 +53,10 @@ fn main() {
             utils::reqwest::Url::parse(&format!("http://{}", service_key.onion_url)).unwrap();
         let _ = onion_url.set_port(Some(hs_port));
         println!(
-        "Hidden Service Created!!\n Hidden Service Onion URL: {}\n Forwarding to Port: {}\n Socks5 Proxy: 127.0.0.1:{}\n",
+        "Hidden Service Created and registered, it will be restored on next start!!\n Hidden Service Onion URL: {}\n Forwarding to Port: {}\n Socks5 Proxy: 127.0.0.1:{}\n",
         onion_url, to_port,socks_port
         );
 
-        // TODO write keys + param to file and on open if found prompt to restore
-
         println!("Press \"h\" to add a new service or any other key to exit");
         let mut input = String::new();
         std::io::stdin().read_line(&mut input).unwrap();
@@ -0,0 +1,214 @@
+//! Scriptable fake Tor control port for unit tests
+//! Speaks enough of the control protocol for Torut and ControlConn: PROTOCOLINFO, cookie
+//! AUTHENTICATE, TAKEOWNERSHIP, GETINFO, SETEVENTS, ADD_ONION, DEL_ONION and async events.
+//! Any command can have its reply scripted (including malformed ones) with `reply`.
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use torut::onion::TorSecretKeyV3;
+
+const COOKIE: [u8; 32] = [7; 32];
+const FALLBACK_SERVICE_ID: &str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+
+#[derive(Default)]
+struct MockState {
+    // GETINFO key -> successive values, the last one is repeated
+    getinfo: HashMap<String, (Vec<String>, usize)>,
+    // command prefix -> raw reply (with CRLFs) overriding the default behaviour
+    replies: Vec<(String, String)>,
+    received: Vec<String>,
+    subscribers: Vec<TcpStream>,
+    reject_auth: bool,
+}
+
+pub(crate) struct MockControlPort {
+    port: u16,
+    state: Arc<Mutex<MockState>>,
+    cookie_path: String,
+}
+
+impl MockControlPort {
+    pub(crate) fn new() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let cookie_dir = "/tmp/sifir_rs_sdk_mock_ctl";
+        fs::create_dir_all(cookie_dir).unwrap();
+        let cookie_path = format!("{}/{}.cookie", cookie_dir, port);
+        fs::write(&cookie_path, COOKIE).unwrap();
+
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let server_state = state.clone();
+        let server_cookie_path = cookie_path.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let state = server_state.clone();
+                let cookie_path = server_cookie_path.clone();
+                std::thread::spawn(move || handle_connection(stream, state, cookie_path));
+            }
+        });
+        MockControlPort {
+            port,
+            state,
+            cookie_path,
+        }
+    }
+
+    /// Mock of a fully bootstrapped daemon, enough for `into_owned_node` without optional listeners
+    pub(crate) fn bootstrapped() -> Self {
+        Self::new()
+            .getinfo(
+                "status/bootstrap-phase",
+                &["NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\""],
+            )
+            .getinfo("version", &["0.4.5.8"])
+    }
+
+    /// Successive values returned for GETINFO key, the last one keeps being returned
+    pub(crate) fn getinfo(self, key: &str, values: &[&str]) -> Self {
+        self.state.lock().unwrap().getinfo.insert(
+            String::from(key),
+            (values.iter().map(|v| String::from(*v)).collect(), 0),
+        );
+        self
+    }
+
+    /// Raw reply (CRLF terminated lines) sent for any command starting with `command`
+    pub(crate) fn reply(self, command: &str, reply: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .push((String::from(command), String::from(reply)));
+        self
+    }
+
+    /// Fail every AUTHENTICATE
+    pub(crate) fn reject_auth(self) -> Self {
+        self.state.lock().unwrap().reject_auth = true;
+        self
+    }
+
+    pub(crate) fn control_port(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// Sends an async event line ("HS_DESC UPLOADED ...") to every connection that called SETEVENTS
+    pub(crate) fn push_event(&self, event: &str) {
+        let mut state = self.state.lock().unwrap();
+        let line = format!("650 {}\r\n", event);
+        state
+            .subscribers
+            .retain(|mut s| s.write_all(line.as_bytes()).is_ok());
+    }
+
+    /// Every command received so far, across connections
+    pub(crate) fn received_commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().received.clone()
+    }
+}
+
+impl Drop for MockControlPort {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.cookie_path);
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: Arc<Mutex<MockState>>, cookie_path: String) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line.trim_end().to_string(),
+            Err(_) => return,
+        };
+        let reply = {
+            let mut state = state.lock().unwrap();
+            state.received.push(line.clone());
+            match state
+                .replies
+                .iter()
+                .find(|(command, _)| line.starts_with(command.as_str()))
+            {
+                Some((_, reply)) => reply.clone(),
+                None => default_reply(&line, &mut state, &cookie_path, &writer),
+            }
+        };
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn default_reply(
+    line: &str,
+    state: &mut MockState,
+    cookie_path: &str,
+    stream: &TcpStream,
+) -> String {
+    let mut parts = line.splitn(2, ' ');
+    let command = parts.next().unwrap_or("").to_uppercase();
+    let args = parts.next().unwrap_or("").trim();
+    match command.as_str() {
+        "PROTOCOLINFO" => format!(
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"{}\"\r\n250-VERSION Tor=\"0.4.5.8\"\r\n250 OK\r\n",
+            cookie_path
+        ),
+        "AUTHENTICATE" => {
+            let expected: String = COOKIE.iter().map(|b| format!("{:02X}", b)).collect();
+            if !state.reject_auth && args.eq_ignore_ascii_case(&expected) {
+                String::from("250 OK\r\n")
+            } else {
+                String::from("515 Authentication failed: Wrong length on authentication cookie.\r\n")
+            }
+        }
+        "GETINFO" => match state.getinfo.get_mut(args) {
+            Some((values, idx)) => {
+                let value = values[(*idx).min(values.len() - 1)].clone();
+                *idx += 1;
+                format!("250-{}={}\r\n250 OK\r\n", args, value)
+            }
+            None => format!("552 Unrecognized key \"{}\"\r\n", args),
+        },
+        "SETEVENTS" => {
+            if let Ok(subscriber) = stream.try_clone() {
+                state.subscribers.push(subscriber);
+            }
+            String::from("250 OK\r\n")
+        }
+        "ADD_ONION" => format!("250-ServiceID={}\r\n250 OK\r\n", add_onion_service_id(args)),
+        "TAKEOWNERSHIP" | "DEL_ONION" | "RESOLVE" | "SETCONF" | "HSFETCH" => {
+            String::from("250 OK\r\n")
+        }
+        _ => format!("510 Unrecognized command \"{}\"\r\n", command),
+    }
+}
+
+/// Service id Tor would answer for the key of an ADD_ONION, a fixed valid one for NEW: keys
+fn add_onion_service_id(args: &str) -> String {
+    args.split_whitespace()
+        .next()
+        .and_then(|key| key.strip_prefix("ED25519-V3:"))
+        .and_then(|encoded| {
+            let mut secret_key = [0u8; 64];
+            match base64::decode_config_slice(encoded, base64::STANDARD, &mut secret_key) {
+                Ok(64) => Some(secret_key),
+                _ => None,
+            }
+        })
+        .map(|secret_key| {
+            let onion = TorSecretKeyV3::from(secret_key)
+                .public()
+                .get_onion_address();
+            String::from(onion.to_string().trim_end_matches(".onion"))
+        })
+        .unwrap_or_else(|| String::from(FALLBACK_SERVICE_ID))
+}
@@ -0,0 +1,253 @@
+use crate::TorErrors;
+use std::collections::VecDeque;
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
+use torut::control::{Conn, TorAuthData, UnauthenticatedConn};
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+/// Raw control port connection used for the commands and events Torut does not wrap
+/// (RESOLVE results, HS_DESC, ONION_CLIENT_AUTH_*, ...)
+/// Replies are returned as the minimally parsed lines Torut's Conn gives us and async events
+/// received while waiting on a command reply are queued for `next_event`
+pub(crate) struct ControlConn {
+    conn: Conn<TcpStream>,
+    events: VecDeque<Vec<String>>,
+}
+
+impl ControlConn {
+    /// Opens and cookie authenticates a new connection to the control port
+    pub(crate) async fn connect(control_port: &str) -> Result<Self, TorErrors> {
+        // Torut only builds auth data from an UnauthenticatedConn it then keeps the stream of,
+        // so we load the protocol info on a short lived connection and authenticate a new one with it
+        let cookie = {
+            let s = TcpStream::connect(control_port.trim()).await?;
+            let mut utc = UnauthenticatedConn::new(s);
+            let proto_info = utc
+                .load_protocol_info()
+                .await
+                .map_err(TorErrors::ControlConnectionError)?;
+            match proto_info.make_auth_data()? {
+                Some(TorAuthData::Cookie(cookie)) => cookie.into_owned(),
+                _ => {
+                    return Err(TorErrors::BootStrapError(String::from(
+                        "Error making control auth data",
+                    )))
+                }
+            }
+        };
+        let s = TcpStream::connect(control_port.trim()).await?;
+        let mut ctl = ControlConn {
+            conn: Conn::new(s),
+            events: VecDeque::new(),
+        };
+        let hex_cookie: String = cookie.iter().map(|b| format!("{:02X}", b)).collect();
+        ctl.command(&format!("AUTHENTICATE {}", hex_cookie)).await?;
+        Ok(ctl)
+    }
+
+    /// Sends a command and returns the lines of its 250 reply
+    pub(crate) async fn command(&mut self, command: &str) -> Result<Vec<String>, TorErrors> {
+        self.conn
+            .write_data(format!("{}\r\n", command).as_bytes())
+            .await
+            .map_err(TorErrors::ControlConnectionError)?;
+        loop {
+            let (code, lines) = self
+                .conn
+                .receive_data()
+                .await
+                .map_err(TorErrors::ControlConnectionError)?;
+            match code {
+                650 => self.events.push_back(lines),
+                250 => return Ok(lines),
+                _ => return Err(TorErrors::ControlCommandError(code, lines.join("\n"))),
+            }
+        }
+    }
+
+    /// Subscribe this connection to the given async events, replacing any previous subscription
+    pub(crate) async fn set_events(&mut self, events: &[&str]) -> Result<(), TorErrors> {
+        self.command(&format!("SETEVENTS {}", events.join(" ")))
+            .await
+            .map(|_| ())
+    }
+
+    /// Waits for the next async event
+    pub(crate) async fn next_event(&mut self) -> Result<Vec<String>, TorErrors> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let (code, lines) = self
+                .conn
+                .receive_data()
+                .await
+                .map_err(TorErrors::ControlConnectionError)?;
+            if code == 650 {
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// Waits for the first event `matcher` returns Some for, erroring out if deadline is reached
+    pub(crate) async fn wait_event<T, M>(
+        &mut self,
+        deadline: Instant,
+        mut matcher: M,
+    ) -> Result<T, TorErrors>
+    where
+        M: FnMut(&[String]) -> Option<T>,
+    {
+        loop {
+            let event = timeout_at(deadline, self.next_event())
+                .await
+                .map_err(|_| TorErrors::TimeoutError(String::from("Timeout waiting for event")))??;
+            if let Some(result) = matcher(&event) {
+                return Ok(result);
+            }
+        }
+    }
+}
+
+/// Splits a reply line on spaces, keeping quoted strings (and key="quoted values") whole
+pub(crate) fn split_reply_line(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        if escaped {
+            current.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ' ' if !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// Get the value of a KEY=value token
+pub(crate) fn reply_value<'a>(tokens: &'a [String], key: &str) -> Option<&'a str> {
+    tokens.iter().find_map(|t| {
+        let mut kv = t.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) if k.eq_ignore_ascii_case(key) => Some(v),
+            _ => None,
+        }
+    })
+}
+
+/// Parses a Tor "YYYY-MM-DD HH:MM:SS" UTC timestamp into seconds since the unix epoch
+pub(crate) fn parse_utc_timestamp(timestamp: &str) -> Option<u64> {
+    let mut parts = timestamp.trim().splitn(2, ' ');
+    let date: Vec<i64> = parts
+        .next()?
+        .split('-')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    let time: Vec<i64> = parts
+        .next()?
+        .split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let (y, m, d) = (date[0] - if date[1] <= 2 { 1 } else { 0 }, date[1], date[2]);
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    if secs < 0 {
+        None
+    } else {
+        Some(secs as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockControlPort;
+    use super::*;
+    use crate::RUNTIME;
+    use tokio::time::Duration;
+
+    #[test]
+    fn splits_reply_lines() {
+        let tokens = split_reply_line(
+            "ADDRMAP example.com 93.184.216.34 \"2021-05-01 12:00:00\" EXPIRES=\"2021-05-01 16:00:00\" CACHED=\"NO\"",
+        );
+        assert_eq!(tokens[0], "ADDRMAP");
+        assert_eq!(tokens[2], "93.184.216.34");
+        assert_eq!(tokens[3], "2021-05-01 12:00:00");
+        assert_eq!(reply_value(&tokens, "EXPIRES"), Some("2021-05-01 16:00:00"));
+        assert_eq!(reply_value(&tokens, "cached"), Some("NO"));
+        assert_eq!(reply_value(&tokens, "STREAMID"), None);
+    }
+
+    #[test]
+    fn mock_command_errors_and_events() {
+        let mock = MockControlPort::new().reply("RESOLVE", "552 Invalid address\r\n");
+        (*RUNTIME).lock().unwrap().block_on(async {
+            let mut ctl = ControlConn::connect(&mock.control_port()).await.unwrap();
+            assert!(matches!(
+                ctl.command("RESOLVE example.com").await,
+                Err(TorErrors::ControlCommandError(552, _))
+            ));
+            ctl.set_events(&["HS_DESC"]).await.unwrap();
+            mock.push_event("HS_DESC UPLOADED mockserviceid UNKNOWN $ABCD");
+            let deadline = Instant::now() + Duration::from_millis(2000);
+            let event = ctl
+                .wait_event(deadline, |event| {
+                    event.first().filter(|l| l.contains("UPLOADED")).cloned()
+                })
+                .await
+                .unwrap();
+            assert!(event.starts_with("HS_DESC"));
+            // nothing else is coming
+            let deadline = Instant::now() + Duration::from_millis(200);
+            assert!(matches!(
+                ctl.wait_event(deadline, |_| Some(())).await,
+                Err(TorErrors::TimeoutError(_))
+            ));
+        });
+        assert!(mock
+            .received_commands()
+            .contains(&String::from("SETEVENTS HS_DESC")));
+    }
+
+    #[test]
+    fn mock_rejects_bad_cookie() {
+        let mock = MockControlPort::new().reject_auth();
+        (*RUNTIME).lock().unwrap().block_on(async {
+            assert!(matches!(
+                ControlConn::connect(&mock.control_port()).await,
+                Err(TorErrors::ControlCommandError(515, _))
+            ));
+        });
+    }
+
+    #[test]
+    fn parses_utc_timestamps() {
+        assert_eq!(parse_utc_timestamp("1970-01-01 00:00:00"), Some(0));
+        assert_eq!(parse_utc_timestamp("2021-05-01 16:00:00"), Some(1619884800));
+        assert_eq!(parse_utc_timestamp("NEVER"), None);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::control::{parse_utc_timestamp, reply_value, split_reply_line};
+use crate::TorErrors;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Address a RESOLVE request resolved to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ResolvedAddress {
+    // Forward lookup result
+    Ip(IpAddr),
+    // Reverse lookup result
+    Hostname(String),
+}
+
+/// Result of a DNS lookup done through the Tor network
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DnsResolution {
+    pub query: String,
+    pub address: ResolvedAddress,
+    /// Seconds until Tor expires the mapping, None if it never expires
+    pub ttl_secs: Option<u64>,
+}
+
+/// Only allow plain hostnames through to the control port
+pub(crate) fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 253
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Parses an ADDRMAP event for `query` into a DnsResolution
+/// Returns None if the event is for another query, Some(Err) if Tor failed to resolve it
+/// ADDRMAP format: ADDRMAP Address NewAddress Expiry [error=code] [EXPIRES="UTC"] [CACHED=..]
+/// reverse lookups are reported with Address=REVERSE[ip]
+pub(crate) fn parse_addrmap_event(
+    lines: &[String],
+    query: &str,
+    reverse: bool,
+) -> Option<Result<DnsResolution, TorErrors>> {
+    let tokens = split_reply_line(lines.first()?);
+    if tokens.len() < 3 || tokens[0] != "ADDRMAP" {
+        return None;
+    }
+    let address_matches = if reverse {
+        tokens[1] == format!("REVERSE[{}]", query)
+    } else {
+        tokens[1].eq_ignore_ascii_case(query)
+    };
+    if !address_matches {
+        return None;
+    }
+    if tokens[2] == "<error>" || reply_value(&tokens, "error").is_some() {
+        return Some(Err(TorErrors::DnsResolutionError(format!(
+            "Tor could not resolve {}: {}",
+            query,
+            reply_value(&tokens, "error").unwrap_or("unknown error")
+        ))));
+    }
+    let address = if reverse {
+        ResolvedAddress::Hostname(tokens[2].clone())
+    } else {
+        match tokens[2].parse::<IpAddr>() {
+            Ok(ip) => ResolvedAddress::Ip(ip),
+            Err(_) => {
+                return Some(Err(TorErrors::DnsResolutionError(format!(
+                    "Invalid address {} in ADDRMAP",
+                    tokens[2]
+                ))))
+            }
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let ttl_secs = reply_value(&tokens, "EXPIRES")
+        .and_then(parse_utc_timestamp)
+        .map(|expires| expires.saturating_sub(now));
+    Some(Ok(DnsResolution {
+        query: String::from(query),
+        address,
+        ttl_secs,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addrmap_events() {
+        let forward = vec![String::from(
+            "ADDRMAP example.com 93.184.216.34 \"2021-05-01 12:00:00\" EXPIRES=\"2021-05-01 16:00:00\" CACHED=\"NO\"",
+        )];
+        assert!(parse_addrmap_event(&forward, "other.com", false).is_none());
+        let resolution = parse_addrmap_event(&forward, "example.com", false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            resolution.address,
+            ResolvedAddress::Ip("93.184.216.34".parse().unwrap())
+        );
+        // Expiry is in the past
+        assert_eq!(resolution.ttl_secs, Some(0));
+
+        let reverse = vec![String::from(
+            "ADDRMAP REVERSE[93.184.216.34] example.com NEVER CACHED=\"NO\"",
+        )];
+        let resolution = parse_addrmap_event(&reverse, "93.184.216.34", true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            resolution.address,
+            ResolvedAddress::Hostname(String::from("example.com"))
+        );
+        assert_eq!(resolution.ttl_secs, None);
+
+        let failed = vec![String::from(
+            "ADDRMAP nope.example <error> NEVER error=yes CACHED=\"NO\"",
+        )];
+        assert!(matches!(
+            parse_addrmap_event(&failed, "nope.example", false),
+            Some(Err(TorErrors::DnsResolutionError(_)))
+        ));
+        assert!(!is_valid_hostname("example.com\r\nSIGNAL SHUTDOWN"));
+    }
+}
@@ -172,7 +172,9 @@ impl HiddenServiceHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{OwnedTorService, TorHiddenServiceParam, TorService, TorServiceParam};
+    use crate::{
+        testnet, HiddenServicePort, OwnedTorService, TorHiddenServiceParam, TorServiceParam,
+    };
     use logger::Logger;
     use serial_test::serial;
     use std::borrow::{Borrow, BorrowMut};
@@ -181,21 +183,23 @@ mod tests {
     use std::sync::{Arc, Mutex};
 
     #[test]
+    #[serial(tor)]
     fn hidden_service_handler() {
         Logger::new();
         let socks_port = 19054;
         let mut owned_node: OwnedTorService = TorServiceParam {
             socks_port: Some(socks_port),
             data_dir: String::from("/tmp/sifir_rs_sdk"),
-            bootstrap_timeout_ms: Some(45000),
+            bootstrap_timeout_ms: Some(180000),
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
         let service_key = owned_node
             .create_hidden_service(TorHiddenServiceParam {
-                to_port: 20000,
-                hs_port: 20011,
-                secret_key: None,
+                ports: vec![HiddenServicePort::local(20016, 20006)],
+                ..Default::default()
             })
             .unwrap();
         assert!(service_key.onion_url.to_string().contains(".onion"));
@@ -222,7 +226,7 @@ mod tests {
             count: count.clone(),
         };
 
-        let mut listner = HiddenServiceHandler::new(20000).unwrap();
+        let mut listner = HiddenServiceHandler::new(20006).unwrap();
         let _ = listner.set_data_handler(obv).unwrap();
         let _ = listner.start_http_listener();
 
@@ -234,7 +238,7 @@ mod tests {
                     service_key.onion_url
                 ))
                 .unwrap();
-                let _ = onion_url.set_port(Some(20011));
+                let _ = onion_url.set_port(Some(20016));
 
                 let resp = client
                     .post(onion_url)
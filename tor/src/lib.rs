@@ -1,25 +1,45 @@
+mod control;
+pub mod dns;
 pub mod hidden_service;
+pub mod onion;
+pub mod recovery;
 pub mod tcp_stream;
+#[cfg(any(test, feature = "testnet"))]
+pub mod testnet;
+use control::ControlConn;
+use dns::DnsResolution;
 use futures::{Future, TryStreamExt};
 use lazy_static::*;
 use libtor::{Tor, TorAddress, TorBool, TorFlag};
 use logger::log::*;
+use onion::client_auth::OnionClientAuth;
+use onion::disk::DiskHiddenService;
+use onion::health::{HealthMonitor, HealthMonitorParam, HealthObserver};
+use onion::keystore::KeyWrapper;
+use onion::migration::OnionMigration;
+use onion::probe::{OnionProbe, ProbeOutcome};
+use onion::registry::{OnionRegistry, RegisteredHiddenService, RegistryEntry};
+use onion::{HiddenServiceTarget, OnionAddress, OnionServiceDescriptor, OnionServiceOptions};
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::fs;
 use std::io;
 use std::io::{Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::net::{TcpListener, ToSocketAddrs};
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use tcp_stream::TcpSocksStream;
+#[cfg(any(test, feature = "testnet"))]
+use testnet::TestNetworkParam;
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio::task::JoinError;
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout, Duration, Instant};
 use tokio_compat_02::FutureExt;
 use torut::control::{AsyncEvent, AuthenticatedConn, ConnError, UnauthenticatedConn};
 use torut::onion::TorSecretKeyV3;
@@ -27,6 +47,9 @@ use torut::onion::TorSecretKeyV3;
 type F = Box<dyn Fn(AsyncEvent<'static>) -> Pin<Box<dyn Future<Output = Result<(), ConnError>>>>>;
 type G = AuthenticatedConn<TcpStream, F>;
 
+/// How long to wait for Tor to answer a RESOLVE request
+const DNS_RESOLVE_TIMEOUT_MS: u64 = 30000;
+
 lazy_static! {
     pub static ref RUNTIME: Mutex<tokio::runtime::Runtime> = Mutex::new(
         tokio::runtime::Builder::new_multi_thread()
@@ -45,11 +68,40 @@ lazy_static! {
 }
 
 #[repr(C)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct TorServiceParam {
     pub socks_port: Option<u16>,
     pub data_dir: String,
     pub bootstrap_timeout_ms: Option<u64>,
+    /// Only allow traffic to onion services, Tor will refuse any clearnet exit on the SocksPort
+    #[serde(default)]
+    pub onion_only: bool,
+    /// Open a DNSPort resolving through Tor, used to route all app traffic (VPN mode)
+    #[serde(default)]
+    pub dns_port: Option<u16>,
+    /// Open a TransPort for transparently proxied TCP connections (VPN mode)
+    #[serde(default)]
+    pub trans_port: Option<u16>,
+    /// Map resolved hostnames to virtual addresses so TransPort connections keep the hostname
+    #[serde(default)]
+    pub automap_hosts_on_resolve: bool,
+    /// Virtual address range used by AutomapHostsOnResolve, Tor defaults to 127.192.0.0/10
+    #[serde(default)]
+    pub virtual_addr_network_ipv4: Option<String>,
+    /// Open an HTTPTunnelPort for clients that only speak HTTP CONNECT
+    #[serde(default)]
+    pub http_tunnel_port: Option<u16>,
+    /// Onion services Tor publishes from a HiddenServiceDir, see
+    /// OwnedTorService::disk_hidden_service
+    #[serde(default)]
+    pub disk_hidden_services: Vec<DiskHiddenService>,
+    /// Connect to a private test network (see testnet::LocalTorNetwork) instead of the public Tor network
+    #[cfg(any(test, feature = "testnet"))]
+    #[serde(default)]
+    pub test_network: Option<TestNetworkParam>,
+    /// Publish onion services without location anonymity, never read from JSON
+    #[serde(skip)]
+    pub non_anonymous_server: Option<NonAnonymousServer>,
 }
 
 impl TorServiceParam {
@@ -58,29 +110,120 @@ impl TorServiceParam {
             data_dir: String::from(data_dir),
             socks_port: Some(socks_port),
             bootstrap_timeout_ms: Some(bootstap_timeout_ms),
+            ..Default::default()
         }
     }
 }
 
+/// Opt-in to run the node as a non anonymous server: its onion services are single onion
+/// services (HiddenServiceSingleHopMode and HiddenServiceNonAnonymousMode), Tor connects straight
+/// to introduction and rendezvous points which cuts latency but shows them the IP of the server.
+/// Only for always-on servers whose location is no secret. Tor requires every client port to be
+/// off in this mode so the node has no SocksPort and can't reach other onions.
+/// It can only be built with `opt_in` in Rust, JSON params (and so the mobile FFI) can't enable it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonAnonymousServer {
+    _private: (),
+}
+
+impl NonAnonymousServer {
+    pub fn opt_in() -> Self {
+        NonAnonymousServer { _private: () }
+    }
+}
+
 pub struct TorService {
     socks_port: u16,
     control_port: String,
     bootstrap_timeout_ms: u64,
+    onion_only: bool,
+    non_anonymous: bool,
+    /// Kinds of the optional listeners (dns, trans, httptunnel) a port was given for
+    requested_listeners: Vec<&'static str>,
+    disk_hidden_services: Vec<DiskHiddenService>,
+    base_dir: String,
     _handle: Option<JoinHandle<Result<u8, libtor::Error>>>,
 }
 
 pub struct OwnedTorService {
     pub socks_port: u16,
     pub control_port: String,
+    pub onion_only: bool,
+    /// Runs single onion services, see NonAnonymousServer. socks_port is 0
+    pub non_anonymous: bool,
+    /// Address the DNSPort is bound to if one was requested
+    pub dns_listener: Option<SocketAddr>,
+    /// Address the TransPort is bound to if one was requested
+    pub trans_listener: Option<SocketAddr>,
+    /// Address the HTTPTunnelPort is bound to if one was requested
+    pub http_tunnel_listener: Option<SocketAddr>,
+    /// Version the daemon reports, empty if it couldn't be read
+    pub tor_version: String,
     _handle: Option<JoinHandle<Result<u8, libtor::Error>>>,
     _ctl: RefCell<Option<G>>,
+    // Raw connection our onion services are added on, ephemeral services live as long as it does.
+    // Shared with the health monitor, which republishes services on it
+    _onion_ctl: Arc<Mutex<Option<ControlConn>>>,
+    hosted_services: Arc<Mutex<HashMap<String, HostedService>>>,
+    registry: OnionRegistry,
+    // Wraps the registry keys at rest once unlocked
+    keystore: Option<Box<dyn KeyWrapper>>,
+    disk_hidden_services: Vec<DiskHiddenService>,
+    base_dir: String,
 }
 
+/// Maps a virtual port of an onion service to the target connections are forwarded to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HiddenServicePort {
+    pub hs_port: u16,
+    pub target: HiddenServiceTarget,
+}
+
+impl HiddenServicePort {
+    /// Forward hs_port to to_port on 127.0.0.1
+    pub fn local(hs_port: u16, to_port: u16) -> Self {
+        HiddenServicePort {
+            hs_port,
+            target: HiddenServiceTarget::local(to_port),
+        }
+    }
+}
+
+/// Onion service to publish with ADD_ONION.
+/// ADD_ONION has no flag for the introduction point DoS defense (HiddenServiceEnableIntroDoSDefense
+/// and its rate/burst), services needing it go in TorServiceParam.disk_hidden_services
 #[repr(C)]
+#[derive(Deserialize, Default)]
+#[serde(default)]
 pub struct TorHiddenServiceParam {
-    pub to_port: u16,
-    pub hs_port: u16,
+    /// Port mappings served by the onion, the returned onion_url uses the first one
+    pub ports: Vec<HiddenServicePort>,
+    #[serde(skip)]
     pub secret_key: Option<[u8; 64]>,
+    /// Keep publishing the service if our control connection is reset, it is still removed when
+    /// the daemon shuts down
+    pub detached: bool,
+    /// Streams a client may open per rendezvous circuit, None or 0 for unlimited
+    pub max_streams: Option<u16>,
+    /// Close the whole circuit of a client going over max_streams rather than only refusing
+    /// the stream
+    pub max_streams_close_circuit: bool,
+}
+
+/// What we need to republish a service we created
+#[derive(Clone)]
+pub(crate) struct HostedService {
+    pub(crate) secret_key: [u8; 64],
+    pub(crate) ports: Vec<HiddenServicePort>,
+    pub(crate) options: OnionServiceOptions,
+    /// Set once rotated, unix time in seconds it should be deleted at
+    pub(crate) retire_at: Option<u64>,
+}
+
+impl HostedService {
+    pub(crate) fn add_onion_command(&self) -> Result<String, TorErrors> {
+        onion::add_onion_command(&self.secret_key.into(), &self.ports, &self.options)
+    }
 }
 
 pub struct TorHiddenService {
@@ -115,6 +258,10 @@ trait TorControlApi {
     fn get_status(
         &mut self,
     ) -> Pin<Box<dyn Future<Output = Result<OwnedTorServiceBootstrapPhase, TorErrors>> + '_>>;
+    fn get_listener(
+        &mut self,
+        kind: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<SocketAddr>, TorErrors>> + '_>>;
 }
 
 #[derive(Error, Debug)]
@@ -131,6 +278,36 @@ pub enum TorErrors {
     ThreadingError(#[from] JoinError),
     #[error("Error TcpStream:")]
     TcpStreamError(String),
+    #[error("Error target is not an onion address:")]
+    NonOnionTargetError(String),
+    #[error("Control command error {}: {}",.0, .1)]
+    ControlCommandError(u16, String),
+    #[error("Error timeout:")]
+    TimeoutError(String),
+    #[error("Error resolving address:")]
+    DnsResolutionError(String),
+    #[error("Error data directory is in use by a running daemon:")]
+    DataDirectoryInUse(String),
+    #[error("Error recovering data directory:")]
+    DataDirRecoveryError(String),
+    #[error("Error with local test network:")]
+    TestNetworkError(String),
+    #[error("Error with hidden service param:")]
+    HiddenServiceParamError(String),
+    #[error("Error with client authorization:")]
+    ClientAuthError(String),
+    #[error("Error with onion service registry:")]
+    OnionRegistryError(String),
+    #[error("Error with onion keystore:")]
+    KeystoreError(String),
+    #[error("Error with onion key:")]
+    OnionKeyError(String),
+    #[error("Error invalid onion address:")]
+    OnionAddressError(String),
+    #[error("Error with non anonymous server mode:")]
+    NonAnonymousServerError(String),
+    #[error("Error with onion migration:")]
+    OnionMigrationError(String),
 }
 
 /// Convert Torservice Param into an Unauthentication TorService:
@@ -140,18 +317,36 @@ pub enum TorErrors {
 impl TryFrom<TorServiceParam> for TorService {
     type Error = TorErrors;
     fn try_from(param: TorServiceParam) -> Result<Self, Self::Error> {
+        let non_anonymous = param.non_anonymous_server.is_some();
+        if non_anonymous {
+            check_non_anonymous_server(&param)?;
+        }
         let mut service = Tor::new();
-        let socks_port = param.socks_port.unwrap_or(19051);
+        let socks_port = if non_anonymous {
+            0
+        } else {
+            param.socks_port.unwrap_or(19051)
+        };
         let base_dir = format!("{}/sifir_sdk/tor", param.data_dir);
         let data_dir = format!("{}/data", base_dir);
         let cache_dir = format!("{}/cache", base_dir);
         let ctl_file_path = format!("{}/ctl.info", base_dir);
+        let client_auth_dir = format!("{}/onion_auth", base_dir);
         let info_log_path = format!("{}/logs/sifir_tor_log.info", base_dir);
         let error_log_path = format!("{}/logs/sifir_tor_log.err", base_dir);
         // Create directories
         fs::create_dir_all(data_dir.clone())?;
         fs::create_dir_all(format!("{}/logs", base_dir))?;
         fs::create_dir_all(cache_dir.clone())?;
+        fs::create_dir_all(client_auth_dir.clone())?;
+        // Tor refuses to load permanent client credentials from a dir others can read
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&client_auth_dir, fs::Permissions::from_mode(0o700))?;
+        }
+        // Repair leftovers of a killed process before the daemon trips over them
+        let _ = recovery::recover_data_dir(&base_dir)?;
         // Setup logfiles
         // Create logfile if not existing to avoid issues with mobile
         // Vector Of Results -> Result of Vectors
@@ -182,11 +377,67 @@ impl TryFrom<TorServiceParam> for TorService {
             //.flag(TorFlag::DataDirectoryGroupReadable(TorBool::True))
             .flag(TorFlag::CacheDirectory(cache_dir))
             //.flag(TorFlag::CacheDirectoryGroupReadable("1".into()))
-            .flag(TorFlag::SocksPort(socks_port))
             .flag(TorFlag::ControlPortAuto)
             .flag(TorFlag::CookieAuthentication(libtor::TorBool::True))
             .flag(TorFlag::ControlPortWriteToFile(ctl_file_path.clone()))
-            .flag(TorFlag::ControlPortFileGroupReadable(libtor::TorBool::True));
+            .flag(TorFlag::ControlPortFileGroupReadable(libtor::TorBool::True))
+            .flag(TorFlag::Custom(format!(
+                "ClientOnionAuthDir {}",
+                client_auth_dir
+            )));
+        if non_anonymous {
+            service
+                .flag(TorFlag::Custom(String::from("SocksPort 0")))
+                .flag(TorFlag::Custom(String::from(
+                    "HiddenServiceSingleHopMode 1",
+                )))
+                .flag(TorFlag::Custom(String::from(
+                    "HiddenServiceNonAnonymousMode 1",
+                )));
+        } else if param.onion_only {
+            // In onion only mode Tor itself refuses to open clearnet exit streams from the
+            // SocksPort
+            service.flag(TorFlag::Custom(format!(
+                "SocksPort {} OnionTrafficOnly ExtendedErrors",
+                socks_port
+            )));
+        } else {
+            // ExtendedErrors, set on both SocksPorts, tells onion failures apart in SOCKS replies,
+            // see probe_onion
+            service.flag(TorFlag::Custom(format!(
+                "SocksPort {} ExtendedErrors",
+                socks_port
+            )));
+        }
+        // VPN mode listeners
+        if let Some(dns_port) = param.dns_port {
+            service.flag(TorFlag::Custom(format!("DNSPort {}", dns_port)));
+        }
+        if let Some(trans_port) = param.trans_port {
+            service.flag(TorFlag::Custom(format!("TransPort {}", trans_port)));
+        }
+        if param.automap_hosts_on_resolve {
+            service.flag(TorFlag::Custom(String::from("AutomapHostsOnResolve 1")));
+        }
+        if let Some(network) = param.virtual_addr_network_ipv4 {
+            service.flag(TorFlag::Custom(format!("VirtualAddrNetworkIPv4 {}", network)));
+        }
+        if let Some(http_tunnel_port) = param.http_tunnel_port {
+            service.flag(TorFlag::Custom(format!("HTTPTunnelPort {}", http_tunnel_port)));
+        }
+        for disk_service in &param.disk_hidden_services {
+            disk_service.create_dir(&base_dir)?;
+            for line in disk_service.torrc_lines(&base_dir)? {
+                service.flag(TorFlag::Custom(line));
+            }
+        }
+        #[cfg(any(test, feature = "testnet"))]
+        if let Some(test_network) = param.test_network {
+            service.flag(TorFlag::Custom(String::from("TestingTorNetwork 1")));
+            for dir_authority in test_network.dir_authorities {
+                service.flag(TorFlag::Custom(format!("DirAuthority {}", dir_authority)));
+            }
+        }
         // // Android logging to android
         // #[cfg(target_os = "android")]
         // {
@@ -203,42 +454,73 @@ impl TryFrom<TorServiceParam> for TorService {
         let mut is_ready = false;
         let mut control_port = String::new();
         let mut try_times = 0;
-        // We wait for Tor to write the new config file otherwise we risk reading the old config and port.
-        // Anything less than a second and iOS errors out
-        // TODO Anyway to *know* when the new config has been written besides checking config file modifed after starting process?
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+        // Any stale control file was removed by the recovery phase, so the file we read here
+        // was written by the daemon we just started. It may still be half written though.
         while !is_ready {
-            let contents = fs::read_to_string(ctl_file_path.clone());
-            match contents {
-                Ok(t) => {
-                    if !t.contains("PORT=") {
-                        return Err(TorErrors::BootStrapError(String::from("No port in config")));
-                    };
-                    let data: Vec<&str> = t.split("PORT=").collect();
-                    control_port = data[1].into();
+            let control_addr = match fs::read_to_string(&ctl_file_path) {
+                Ok(contents) => recovery::parse_control_port_file(&contents)
+                    .ok_or_else(|| format!("no control port in {:?}", contents)),
+                Err(e) => Err(e.to_string()),
+            };
+            match control_addr {
+                Ok(addr) => {
+                    control_port = addr.to_string();
                     info!("success with config port {}!", control_port);
                     is_ready = true;
                 }
-                Err(e) => {
+                Err(cause) => {
                     try_times += 1;
                     if try_times > 10 {
-                        return Err(TorErrors::BootStrapError(String::from(
-                            "Unable to read daemon control info",
+                        return Err(TorErrors::DataDirRecoveryError(format!(
+                            "Unable to read daemon control info from {}: {}",
+                            ctl_file_path, cause
                         )));
                     }
+                    std::thread::sleep(std::time::Duration::from_millis(900));
                 }
             }
-            std::thread::sleep(std::time::Duration::from_millis(900));
         }
 
+        let requested_listeners = [
+            ("dns", param.dns_port.is_some()),
+            ("trans", param.trans_port.is_some()),
+            ("httptunnel", param.http_tunnel_port.is_some()),
+        ]
+        .iter()
+        .filter(|(_, requested)| *requested)
+        .map(|(kind, _)| *kind)
+        .collect();
         Ok(TorService {
             socks_port,
             control_port,
             bootstrap_timeout_ms: param.bootstrap_timeout_ms.unwrap_or(45000),
+            onion_only: param.onion_only,
+            non_anonymous,
+            requested_listeners,
+            disk_hidden_services: param.disk_hidden_services,
+            base_dir,
             _handle: Some(handle),
         })
     }
 }
+/// Tor refuses to start a non anonymous server with client ports, say which one rather than
+/// letting the bootstrap time out
+fn check_non_anonymous_server(param: &TorServiceParam) -> Result<(), TorErrors> {
+    let client_ports = [
+        ("socks_port", param.socks_port.is_some()),
+        ("onion_only", param.onion_only),
+        ("dns_port", param.dns_port.is_some()),
+        ("trans_port", param.trans_port.is_some()),
+        ("http_tunnel_port", param.http_tunnel_port.is_some()),
+    ];
+    match client_ports.iter().find(|(_, set)| *set) {
+        Some((name, _)) => Err(TorErrors::NonAnonymousServerError(format!(
+            "{} can't be used on a non anonymous server",
+            name
+        ))),
+        None => Ok(()),
+    }
+}
 /// Async handler injected into Torut to recieve Tor daemon async events
 /// Right now does nothing but is needed for AuthenticatedConnection from Torut to function correctly
 fn handler(_: AsyncEvent<'static>) -> Pin<Box<dyn Future<Output = Result<(), ConnError>> + '_>> {
@@ -277,11 +559,31 @@ impl TorService {
         Ok(ac)
     }
 
+    /// Address Tor bound a listener we asked for to, None if no port was given for it.
+    /// A failed lookup is only logged, the daemon is bootstrapped and usable without it
+    async fn requested_listener<T: TorControlApi>(
+        &self,
+        ac: &mut T,
+        kind: &'static str,
+    ) -> Option<SocketAddr> {
+        if !self.requested_listeners.contains(&kind) {
+            return None;
+        }
+        match ac.get_listener(kind).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Unable to get the {} listener: {:?}", kind, e);
+                None
+            }
+        }
+    }
+
     /// Converts TorService to OwnedTorService, consuming the TorService
     /// and returning an OwnedTorService which is fully bootstrapped and under our control
     /// (If we drop this object the Tor daemon will shut down)
     pub fn into_owned_node(self) -> Result<OwnedTorService, TorErrors> {
-        (*RUNTIME).lock().unwrap().block_on(
+        let registry = OnionRegistry::load(&self.base_dir)?;
+        let owned_result: Result<OwnedTorService, TorErrors> = (*RUNTIME).lock().unwrap().block_on(
             async {
                 let mut ac = self
                     .get_control_auth_conn(Some(Box::new(handler) as F))
@@ -293,15 +595,41 @@ impl TorService {
                     .await
                     .map_err(TorErrors::ControlConnectionError)?;
                 ac.wait_bootstrap(Some(self.bootstrap_timeout_ms)).await?;
+                let dns_listener = self.requested_listener(&mut ac, "dns").await;
+                let trans_listener = self.requested_listener(&mut ac, "trans").await;
+                let http_tunnel_listener = self.requested_listener(&mut ac, "httptunnel").await;
+                let tor_version = match ac.get_info("version").await {
+                    Ok(version) => version,
+                    Err(e) => {
+                        warn!("Unable to get the Tor version: {:?}", e);
+                        String::new()
+                    }
+                };
+                let onion_ctl = ControlConn::connect(&self.control_port).await?;
                 Ok(OwnedTorService {
                     socks_port: self.socks_port,
                     control_port: self.control_port,
+                    onion_only: self.onion_only,
+                    non_anonymous: self.non_anonymous,
+                    dns_listener,
+                    trans_listener,
+                    http_tunnel_listener,
+                    tor_version,
                     _handle: self._handle,
                     _ctl: RefCell::new(Some(ac)),
+                    _onion_ctl: Arc::new(Mutex::new(Some(onion_ctl))),
+                    hosted_services: Arc::new(Mutex::new(HashMap::new())),
+                    registry,
+                    keystore: None,
+                    disk_hidden_services: self.disk_hidden_services,
+                    base_dir: self.base_dir,
                 })
             }
             .compat(),
-        )
+        );
+        let mut owned_node = owned_result?;
+        owned_node.publish_registered_services();
+        Ok(owned_node)
     }
 }
 
@@ -324,64 +652,574 @@ impl OwnedTorService {
         &mut self,
         param: TorHiddenServiceParam,
     ) -> Result<TorHiddenService, TorErrors> {
-        (*RUNTIME).lock().unwrap().block_on(
+        let first_port = param
+            .ports
+            .first()
+            .ok_or_else(|| {
+                TorErrors::HiddenServiceParamError(String::from("No port mapping given"))
+            })?
+            .hs_port;
+        let service_key: TorSecretKeyV3 = match param.secret_key {
+            Some(key) => key.into(),
+            _ => TorSecretKeyV3::generate(),
+        };
+        let options = OnionServiceOptions {
+            detached: param.detached,
+            max_streams: param.max_streams,
+            max_streams_close_circuit: param.max_streams_close_circuit,
+            non_anonymous: self.non_anonymous,
+        };
+        // Torut's add_onion_v3 only takes SocketAddr targets so ADD_ONION is sent raw
+        self.onion_command(onion::add_onion_command(
+            &service_key,
+            &param.ports,
+            &options,
+        )?)?;
+
+        info!("Hidden service created!");
+        let onion_address = service_key.public().get_onion_address().to_string();
+        let secret_key = service_key.as_bytes();
+        self.hosted_services.lock().unwrap().insert(
+            onion::service_id(&onion_address)?,
+            HostedService {
+                secret_key,
+                ports: param.ports,
+                options,
+                retire_at: None,
+            },
+        );
+        Ok(TorHiddenService {
+            onion_url: TorAddress::AddressPort(onion_address, first_port),
+            secret_key,
+        })
+    }
+
+    /// Creates a service and only returns once enough HSDirs accepted its descriptor (HS_DESC
+    /// UPLOADED events), that is once clients can actually reach it. If that doesn't happen
+    /// within timeout the service is deleted and a TimeoutError with the upload results returned.
+    pub fn create_hidden_service_and_wait(
+        &mut self,
+        param: TorHiddenServiceParam,
+        timeout: Duration,
+    ) -> Result<TorHiddenService, TorErrors> {
+        // Subscribe before ADD_ONION so no upload event can be missed
+        let mut events = (*RUNTIME).lock().unwrap().block_on(
             async {
-                let mut _ctl = self._ctl.borrow_mut();
-                let ctl = _ctl
-                    .as_mut()
-                    .ok_or(TorErrors::BootStrapError(String::from("Error mut lock")))?;
+                let mut ctl = ControlConn::connect(&self.control_port).await?;
+                ctl.set_events(&["HS_DESC"]).await?;
+                Ok::<ControlConn, TorErrors>(ctl)
+            }
+            .compat(),
+        )?;
+        let deadline = Instant::now() + timeout;
+        let hidden_service = self.create_hidden_service(param)?;
+        let onion = match &hidden_service.onion_url {
+            TorAddress::AddressPort(onion, _) => onion.clone(),
+            other => other.to_string(),
+        };
+        let mut uploads = onion::hs_desc::DescriptorUploads::new(&onion::service_id(&onion)?);
+        let published = (*RUNTIME).lock().unwrap().block_on(
+            async {
+                events
+                    .wait_event(deadline, |event| {
+                        if uploads.record(event) {
+                            Some(())
+                        } else {
+                            None
+                        }
+                    })
+                    .await
+            }
+            .compat(),
+        );
+        match published {
+            Ok(_) => {
+                info!("Hidden service {} published: {}", onion, uploads);
+                Ok(hidden_service)
+            }
+            Err(e) => {
+                let _ = self.delete_hidden_service(onion.clone());
+                Err(match e {
+                    TorErrors::TimeoutError(_) => TorErrors::TimeoutError(format!(
+                        "{} not published in time: {}",
+                        onion, uploads
+                    )),
+                    e => e,
+                })
+            }
+        }
+    }
+    pub fn delete_hidden_service(&mut self, onion: String) -> Result<(), TorErrors> {
+        let service_id = onion::service_id(&onion)?;
+        // services can only be deleted from the connection that created them, unless detached
+        self.onion_command(format!("DEL_ONION {}", service_id))?;
+        self.hosted_services.lock().unwrap().remove(&service_id);
+        info!("Hidden serviec deleted !");
+        Ok(())
+    }
 
-                let service_key = match param.secret_key {
-                    Some(key) => key.into(),
-                    _ => TorSecretKeyV3::generate(),
-                };
+    /// Creates a service and records it in the registry so it is published again every time the
+    /// daemon bootstraps, with the same address and ports.
+    /// Its key is sealed with the keystore, which must be unlocked first.
+    pub fn add_registered_hidden_service(
+        &mut self,
+        param: TorHiddenServiceParam,
+    ) -> Result<TorHiddenService, TorErrors> {
+        if self.keystore.is_none() {
+            return Err(TorErrors::KeystoreError(String::from(
+                "Keystore is locked, unlock it before registering services",
+            )));
+        }
+        let hidden_service = self.create_hidden_service(param)?;
+        let onion = match &hidden_service.onion_url {
+            TorAddress::AddressPort(onion, _) => onion.clone(),
+            other => other.to_string(),
+        };
+        let service = self.hosted_service(&onion::service_id(&onion)?)?;
+        let options = service.options;
+        let keystore = self
+            .keystore
+            .as_deref()
+            .ok_or_else(|| TorErrors::KeystoreError(String::from("Keystore is locked")))?;
+        let registered = RegistryEntry::new(&service.secret_key, service.ports.clone(), keystore)
+            .and_then(|entry| {
+                self.registry
+                    .insert(&onion, RegistryEntry { options, ..entry })
+            });
+        if let Err(e) = registered {
+            // Don't leave a service running the app thinks is not registered
+            let _ = self.delete_hidden_service(onion);
+            return Err(e);
+        }
+        Ok(hidden_service)
+    }
+
+    /// Unpublishes a registered service and removes it from the registry
+    pub fn remove_registered_hidden_service(&mut self, onion: &str) -> Result<(), TorErrors> {
+        if !self.registry.remove(onion)? {
+            return Err(TorErrors::OnionRegistryError(format!(
+                "{} is not registered",
+                onion
+            )));
+        }
+        if self
+            .hosted_services
+            .lock()
+            .unwrap()
+            .contains_key(&onion::service_id(onion)?)
+        {
+            self.delete_hidden_service(String::from(onion))?;
+        }
+        Ok(())
+    }
+
+    /// Moves a hosted service to a new key, a generated one if new_secret_key is None, for when
+    /// its key may be compromised. The new service gets the same ports and options and
+    /// is registered if the old one was. Both are served until grace_period is over, then
+    /// retire_rotated_services deletes the old one. Peers check the returned announcement,
+    /// signed with the old key, with OnionMigration::migrate before updating their address.
+    pub fn rotate_hidden_service(
+        &mut self,
+        onion: &str,
+        new_secret_key: Option<[u8; 64]>,
+        grace_period: Duration,
+    ) -> Result<(TorHiddenService, OnionMigration), TorErrors> {
+        let service_id = onion::service_id(onion)?;
+        let old = self.hosted_service(&service_id)?;
+        let new_secret_key = new_secret_key.unwrap_or_else(onion::keys::generate_secret_key);
+        let retire_at = onion::migration::unix_time() + grace_period.as_secs();
+        // signed first so an unusable key leaves nothing behind
+        let migration = OnionMigration::sign(&old.secret_key, &new_secret_key, retire_at)?;
+        let param = TorHiddenServiceParam {
+            ports: old.ports,
+            secret_key: Some(new_secret_key),
+            detached: old.options.detached,
+            max_streams: old.options.max_streams,
+            max_streams_close_circuit: old.options.max_streams_close_circuit,
+        };
+        let hidden_service = if self.registry.contains(&service_id) {
+            let hidden_service = self.add_registered_hidden_service(param)?;
+            self.registry.set_retire_at(&service_id, retire_at)?;
+            hidden_service
+        } else {
+            self.create_hidden_service(param)?
+        };
+        if let Some(service) = self.hosted_services.lock().unwrap().get_mut(&service_id) {
+            service.retire_at = Some(retire_at);
+        }
+        Ok((hidden_service, migration))
+    }
+
+    /// Deletes the services replaced by rotate_hidden_service whose grace period is over and
+    /// returns their onions. Call it periodically, registered ones past it are also dropped when
+    /// the daemon bootstraps.
+    pub fn retire_rotated_services(&mut self) -> Result<Vec<String>, TorErrors> {
+        let now = onion::migration::unix_time();
+        let mut retired: Vec<String> = self
+            .hosted_services
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, service)| service.retire_at.map_or(false, |at| at <= now))
+            .map(|(service_id, _)| service_id.clone())
+            .collect();
+        retired.sort();
+        for service_id in &retired {
+            if self.registry.contains(service_id) {
+                self.remove_registered_hidden_service(service_id)?;
+            } else {
+                self.delete_hidden_service(service_id.clone())?;
+            }
+        }
+        Ok(retired
+            .into_iter()
+            .map(|service_id| format!("{}.onion", service_id))
+            .collect())
+    }
+
+    /// Services of the registry, their keys are not returned
+    pub fn list_registered_hidden_services(&self) -> Vec<RegisteredHiddenService> {
+        self.registry.list()
+    }
+
+    /// Onion of a service given in TorServiceParam.disk_hidden_services, Tor generated its key when
+    /// it first started the service and keeps it in the HiddenServiceDir
+    pub fn disk_hidden_service(&self, name: &str) -> Result<OnionAddress, TorErrors> {
+        self.disk_hidden_services
+            .iter()
+            .find(|service| service.name == name)
+            .ok_or_else(|| {
+                TorErrors::HiddenServiceParamError(format!("No disk hidden service {}", name))
+            })?
+            .onion(&self.base_dir)
+    }
+
+    /// Onion services Tor is actually publishing: the ones added on our onion control connection
+    /// (GETINFO onions/current) and all the detached ones (GETINFO onions/detached), so the app
+    /// can reconcile them with the services it expects
+    pub fn list_onions(&self) -> Result<Vec<OnionServiceDescriptor>, TorErrors> {
+        let mut onions = Vec::new();
+        for (key, detached) in &[("onions/current", false), ("onions/detached", true)] {
+            let lines = match self.onion_command(format!("GETINFO {}", key)) {
+                Ok(lines) => lines,
+                // Tor errors out rather than returning an empty list
+                Err(TorErrors::ControlCommandError(551, ref message))
+                    if message.contains("No onion services") =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            let hosted_services = self.hosted_services.lock().unwrap();
+            for service_id in onion::parse_onions_getinfo(key, &lines) {
+                let hosted = hosted_services.get(&service_id);
+                onions.push(OnionServiceDescriptor {
+                    onion: format!("{}.onion", service_id),
+                    detached: *detached,
+                    hosted: hosted.is_some(),
+                    ports: hosted.map(|s| s.ports.clone()).unwrap_or_default(),
+                    registered: self.registry.contains(&service_id),
+                });
+            }
+        }
+        Ok(onions)
+    }
+
+    /// Unlocks the keystore wrapping registry keys at rest, see onion::keystore, then publishes
+    /// the registered services that could not be opened until now. Fails leaving the keystore
+    /// locked if the wrapper can't open the keys already sealed.
+    pub fn unlock_keystore(&mut self, wrapper: Box<dyn KeyWrapper>) -> Result<(), TorErrors> {
+        self.registry.check_keystore(wrapper.as_ref())?;
+        self.keystore = Some(wrapper);
+        self.publish_registered_services();
+        Ok(())
+    }
+
+    /// Seals the registry keys with a new passphrase or wrapping key, the keystore must be unlocked
+    pub fn change_keystore_key(&mut self, wrapper: Box<dyn KeyWrapper>) -> Result<(), TorErrors> {
+        let current = self
+            .keystore
+            .as_deref()
+            .ok_or_else(|| TorErrors::KeystoreError(String::from("Keystore is locked")))?;
+        self.registry.reseal(current, wrapper.as_ref())?;
+        self.keystore = Some(wrapper);
+        Ok(())
+    }
+
+    /// Explicitly exports the secret key of a service we host, it is not returned anywhere else
+    /// over FFI
+    pub fn export_hidden_service_key(&self, onion: &str) -> Result<[u8; 64], TorErrors> {
+        Ok(self.hosted_service(&onion::service_id(onion)?)?.secret_key)
+    }
 
-                ctl.add_onion_v3(
+    /// Explicitly exports a service we host as a HiddenServiceDir a standalone tor can serve
+    pub fn export_hidden_service_dir(&self, onion: &str, dir: &str) -> Result<(), TorErrors> {
+        onion::keys::write_hidden_service_dir(&self.export_hidden_service_key(onion)?, dir)
+    }
+
+    /// Publishes the registry services not published yet on our onion connection, logging the
+    /// ones that fail. They wait for the keystore to be unlocked to open their keys.
+    fn publish_registered_services(&mut self) {
+        let hosted_services = self.hosted_services.lock().unwrap();
+        let entries: Vec<(String, RegistryEntry)> = self
+            .registry
+            .entries()
+            .filter(|(id, _)| !hosted_services.contains_key(*id))
+            .map(|(id, entry)| (id.clone(), entry.clone()))
+            .collect();
+        drop(hosted_services);
+        for (service_id, entry) in entries {
+            if entry
+                .retire_at
+                .map_or(false, |at| at <= onion::migration::unix_time())
+            {
+                // rotated and its grace period ended while we were down
+                match self.registry.remove(&service_id) {
+                    Ok(_) => info!("Retired rotated service {}", service_id),
+                    Err(e) => warn!("Error retiring service {}: {:?}", service_id, e),
+                }
+                continue;
+            }
+            let keystore = match self.keystore.as_deref() {
+                Some(keystore) => keystore,
+                None => {
+                    info!(
+                        "Registered service {} will be published once the keystore is unlocked",
+                        service_id
+                    );
+                    continue;
+                }
+            };
+            let options = OnionServiceOptions {
+                non_anonymous: self.non_anonymous,
+                ..entry.options
+            };
+            let published = entry.secret_key_bytes(keystore).and_then(|secret_key| {
+                let service_key: TorSecretKeyV3 = secret_key.into();
+                self.onion_command(onion::add_onion_command(
                     &service_key,
-                    false,
-                    false,
-                    false,
-                    None,
-                    &mut [(
-                        param.hs_port,
-                        SocketAddr::new(IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)), param.to_port),
-                    )]
-                    .iter(),
-                )
-                .await
-                .map_err(TorErrors::ControlConnectionError)?;
-
-                info!("Hidden service created!");
-                let onion_url = TorAddress::AddressPort(
-                    service_key.public().get_onion_address().to_string(),
-                    param.hs_port,
-                );
-                let secret_key = service_key.as_bytes();
-                Ok(TorHiddenService {
-                    onion_url,
-                    secret_key,
-                })
+                    &entry.ports,
+                    &options,
+                )?)?;
+                Ok(secret_key)
+            });
+            match published {
+                Ok(secret_key) => {
+                    info!("Republished registered service {}", service_id);
+                    self.hosted_services.lock().unwrap().insert(
+                        service_id,
+                        HostedService {
+                            secret_key,
+                            ports: entry.ports,
+                            options,
+                            retire_at: entry.retire_at,
+                        },
+                    );
+                }
+                Err(e) => warn!(
+                    "Error republishing registered service {}: {:?}",
+                    service_id, e
+                ),
             }
-            .compat(),
-        )
+        }
     }
-    pub fn delete_hidden_service(
+
+    /// Adds the x25519 private key (base32, base64 or a whole `.auth_private` line) used to connect
+    /// to an onion requiring client authorization. Permanent credentials are stored in the
+    /// data dir and survive restarts. Sockets and HTTP requests to the onion then just work.
+    pub fn add_onion_client_auth(
         &mut self,
-        onion: String,
+        onion: &str,
+        x25519_private: &str,
+        permanent: bool,
     ) -> Result<(), TorErrors> {
-        (*RUNTIME).lock().unwrap().block_on(
+        let command =
+            onion::client_auth::onion_client_auth_add_command(onion, x25519_private, permanent)?;
+        match self.onion_command(command) {
+            // 251: credentials for that onion existed and were replaced
+            Ok(_) | Err(TorErrors::ControlCommandError(251, _)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Forgets the client credentials of an onion
+    pub fn remove_onion_client_auth(&mut self, onion: &str) -> Result<(), TorErrors> {
+        match self.onion_command(format!(
+            "ONION_CLIENT_AUTH_REMOVE {}",
+            onion::service_id(onion)?
+        )) {
+            // 251: there were no credentials for that onion
+            Ok(_) | Err(TorErrors::ControlCommandError(251, _)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Onions the client holds credentials for
+    pub fn list_onion_client_auth(&self) -> Result<Vec<OnionClientAuth>, TorErrors> {
+        let lines = self.onion_command(String::from("ONION_CLIENT_AUTH_VIEW"))?;
+        Ok(onion::client_auth::parse_onion_client_auth_view(&lines))
+    }
+
+    fn hosted_service(&self, service_id: &str) -> Result<HostedService, TorErrors> {
+        self.hosted_services
+            .lock()
+            .unwrap()
+            .get(service_id)
+            .cloned()
+            .ok_or_else(|| {
+                TorErrors::HiddenServiceParamError(format!(
+                    "No hidden service {} created",
+                    service_id
+                ))
+            })
+    }
+
+    /// Sends a command on the connection owning our onion services
+    fn onion_command(&self, command: String) -> Result<Vec<String>, TorErrors> {
+        onion_command_on(&self._onion_ctl, &command)
+    }
+
+    /// SocksPort for the features acting as a client, a non anonymous server has none
+    fn client_socks_port(&self) -> Result<u16, TorErrors> {
+        if self.non_anonymous {
+            return Err(TorErrors::NonAnonymousServerError(String::from(
+                "No SocksPort, a non anonymous server can't act as a client",
+            )));
+        }
+        Ok(self.socks_port)
+    }
+
+    /// Opens a TcpSocksStream through our SocksPort, in onion only mode non onion targets are
+    /// rejected with NonOnionTargetError before anything is sent to Tor
+    pub fn tcp_stream(&self, target: String, timeout_ms: u64) -> Result<TcpSocksStream, TorErrors> {
+        let socks_proxy = format!("127.0.0.1:{}", self.client_socks_port()?);
+        TcpSocksStream::new_timeout(target, socks_proxy, timeout_ms, self.onion_only)
+    }
+
+    /// Checks whether a remote onion is online: fetches its descriptor with HSFETCH then, if a port
+    /// is given (or part of onion), asks the SocksPort to connect to it without sending any data.
+    /// An unreachable service is an outcome, errors are for invalid onions and control failures.
+    pub fn probe_onion(
+        &self,
+        onion: &str,
+        port: Option<u16>,
+        timeout: Duration,
+    ) -> Result<OnionProbe, TorErrors> {
+        let socks_port = self.client_socks_port()?;
+        let address: OnionAddress = onion.parse()?;
+        let port = port.or_else(|| address.port());
+        let service_id = address.service_id();
+        let started = Instant::now();
+        let deadline = started + timeout;
+        let fetched = (*RUNTIME).lock().unwrap().block_on(
             async {
-                let mut _ctl = self._ctl.borrow_mut();
-                let ctl = _ctl
-                    .as_mut()
-                    .ok_or(TorErrors::BootStrapError(String::from("Error mut lock")))?;
+                // HS_DESC events are only sent to connections that asked for them
+                let mut ctl = ControlConn::connect(&self.control_port).await?;
+                ctl.set_events(&["HS_DESC", "HS_DESC_CONTENT"]).await?;
+                ctl.command(&format!("HSFETCH {}", service_id)).await?;
+                let fetch = onion::hs_desc::DescriptorFetch::new(&service_id);
+                ctl.wait_event(deadline, |event| fetch.record(event)).await
+            }
+            .compat(),
+        );
+        let mut probe = OnionProbe {
+            onion: format!("{}.onion", service_id),
+            outcome: ProbeOutcome::DescriptorFound,
+            descriptor_ms: None,
+            connect_ms: None,
+            reason: None,
+        };
+        match fetched {
+            Ok(Ok(())) => probe.descriptor_ms = Some(started.elapsed().as_millis() as u64),
+            Ok(Err(reason)) => {
+                probe.outcome = ProbeOutcome::DescriptorNotFound;
+                probe.reason = Some(reason);
+                return Ok(probe);
+            }
+            Err(TorErrors::TimeoutError(_)) => {
+                probe.outcome = ProbeOutcome::TimedOut;
+                probe.reason = Some(String::from("Descriptor fetch timed out"));
+                return Ok(probe);
+            }
+            Err(e) => return Err(e),
+        }
+        let port = match port {
+            Some(port) => port,
+            None => return Ok(probe),
+        };
+        probe.onion = format!("{}.onion:{}", service_id, port);
+        let connect_started = Instant::now();
+        let left = deadline.saturating_duration_since(connect_started);
+        let reply = if left.as_millis() > 0 {
+            onion::probe::socks_connect(
+                SocketAddr::from(([127, 0, 0, 1], socks_port)),
+                &format!("{}.onion", service_id),
+                port,
+                left,
+            )?
+        } else {
+            None
+        };
+        match reply {
+            Some(code) => {
+                probe.connect_ms = Some(connect_started.elapsed().as_millis() as u64);
+                probe.outcome = onion::probe::socks_reply_outcome(code);
+                probe.reason = Some(format!("SOCKS reply {:#04x}", code));
+            }
+            None => {
+                probe.outcome = ProbeOutcome::TimedOut;
+                probe.reason = Some(String::from("No SOCKS reply in time"));
+            }
+        }
+        Ok(probe)
+    }
 
-                ctl.del_onion(&onion)
-                .await
-                .map_err(TorErrors::ControlConnectionError)?;
+    /// Starts checking the services we host in the background, republishing the ones failing
+    /// param.failures_before_republish checks in a row. Checks go through our own SocksPort, so
+    /// they test the circuits a client would build rather than just the descriptor.
+    pub fn start_health_monitor(
+        &self,
+        param: HealthMonitorParam,
+        observer: Box<dyn HealthObserver>,
+    ) -> Result<HealthMonitor, TorErrors> {
+        Ok(HealthMonitor::start(
+            self.client_socks_port()?,
+            self._onion_ctl.clone(),
+            self.hosted_services.clone(),
+            param,
+            observer,
+        ))
+    }
 
-                info!("Hidden serviec deleted !");
-                Ok(())
+    /// Resolve a hostname through the Tor network (control port RESOLVE + ADDRMAP event)
+    /// so the lookup never leaks outside of Tor
+    pub fn resolve(&self, hostname: &str) -> Result<DnsResolution, TorErrors> {
+        if !dns::is_valid_hostname(hostname) {
+            return Err(TorErrors::DnsResolutionError(format!(
+                "Invalid hostname {}",
+                hostname
+            )));
+        }
+        self.resolve_address(String::from(hostname), false)
+    }
+    /// Reverse lookup of an IP through the Tor network
+    pub fn reverse_resolve(&self, ip: IpAddr) -> Result<DnsResolution, TorErrors> {
+        self.resolve_address(ip.to_string(), true)
+    }
+    fn resolve_address(&self, query: String, reverse: bool) -> Result<DnsResolution, TorErrors> {
+        (*RUNTIME).lock().unwrap().block_on(
+            async {
+                // ADDRMAP events are only sent to connections that asked for them so use a
+                // dedicated connection rather than the owned one
+                let mut ctl = ControlConn::connect(&self.control_port).await?;
+                ctl.set_events(&["ADDRMAP"]).await?;
+                let mode = if reverse { "mode=reverse " } else { "" };
+                ctl.command(&format!("RESOLVE {}{}", mode, query)).await?;
+                let deadline = Instant::now() + Duration::from_millis(DNS_RESOLVE_TIMEOUT_MS);
+                ctl.wait_event(deadline, |event| {
+                    dns::parse_addrmap_event(event, &query, reverse)
+                })
+                .await?
             }
             .compat(),
         )
@@ -409,6 +1247,7 @@ impl OwnedTorService {
     /// Then waits on the Tor daemon thread to exit
     pub fn shutdown(&mut self) -> Result<(), TorErrors> {
         {
+            let _ = self._onion_ctl.lock().unwrap().take();
             let _ = self._ctl.borrow_mut().take();
         }
         let _ = self
@@ -478,10 +1317,47 @@ where
             .compat(),
         )
     }
+    fn get_listener(
+        &mut self,
+        kind: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<SocketAddr>, TorErrors>> + '_>> {
+        Box::pin(
+            async move {
+                let listeners = self
+                    .get_info(&format!("net/listeners/{}", kind))
+                    .compat()
+                    .await
+                    .map_err(TorErrors::ControlConnectionError)?;
+                Ok(parse_listener(&listeners))
+            }
+            .compat(),
+        )
+    }
     // dropping the control connection after having taken ownership of the node will cause the node
     // to shutdown
     fn shutdown(self) {}
 }
+/// Parses the first address of a GETINFO net/listeners/* reply ("127.0.0.1:5400" "[::1]:5400")
+fn parse_listener(listeners: &str) -> Option<SocketAddr> {
+    listeners
+        .split_whitespace()
+        .find_map(|l| l.trim_matches('"').parse::<SocketAddr>().ok())
+}
+/// Sends a command on the connection owning our onion services. The connection is locked before
+/// the runtime, always in that order, as the health monitor thread uses it too
+pub(crate) fn onion_command_on(
+    onion_ctl: &Mutex<Option<ControlConn>>,
+    command: &str,
+) -> Result<Vec<String>, TorErrors> {
+    let mut onion_ctl = onion_ctl.lock().unwrap();
+    let ctl = onion_ctl
+        .as_mut()
+        .ok_or_else(|| TorErrors::BootStrapError(String::from("Onion connection closed")))?;
+    (*RUNTIME)
+        .lock()
+        .unwrap()
+        .block_on(async { ctl.command(command).await }.compat())
+}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -492,15 +1368,812 @@ mod tests {
     use std::io::{Read, Write};
     use std::net::{TcpListener, ToSocketAddrs};
 
+    fn mock_service(mock: &control::mock::MockControlPort) -> TorService {
+        let base_dir = format!("/tmp/sifir_rs_sdk_mock/{}", mock.control_port());
+        let _ = fs::remove_dir_all(&base_dir);
+        fs::create_dir_all(&base_dir).unwrap();
+        mock_service_in(mock, &base_dir)
+    }
+
+    fn test_keystore() -> Box<dyn KeyWrapper> {
+        Box::new(onion::keystore::WrappingKey::new([5u8; 32]))
+    }
+
+    fn mock_service_in(mock: &control::mock::MockControlPort, base_dir: &str) -> TorService {
+        TorService {
+            socks_port: 19051,
+            control_port: mock.control_port(),
+            bootstrap_timeout_ms: 1000,
+            onion_only: false,
+            non_anonymous: false,
+            requested_listeners: vec![],
+            disk_hidden_services: vec![],
+            base_dir: String::from(base_dir),
+            _handle: None,
+        }
+    }
+
+    #[test]
+    fn mock_bootstrap_and_status() {
+        let mock = control::mock::MockControlPort::new().getinfo(
+            "status/bootstrap-phase",
+            &[
+                "NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\"",
+                "NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"",
+            ],
+        );
+        let service = mock_service(&mock);
+        (*RUNTIME).lock().unwrap().block_on(
+            async {
+                let mut control_conn = service.get_control_auth_conn(Some(handler)).await.unwrap();
+                control_conn.take_ownership().await.unwrap();
+                assert!(control_conn.wait_bootstrap(Some(5000)).await.unwrap());
+                let status = control_conn.get_status().await.unwrap();
+                assert!(matches!(status, OwnedTorServiceBootstrapPhase::Done));
+            }
+            .compat(),
+        );
+        assert!(mock
+            .received_commands()
+            .contains(&String::from("TAKEOWNERSHIP")));
+    }
+
+    #[test]
+    fn mock_bootstrap_timeout() {
+        let mock = control::mock::MockControlPort::new().getinfo(
+            "status/bootstrap-phase",
+            &["NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\""],
+        );
+        let service = mock_service(&mock);
+        (*RUNTIME).lock().unwrap().block_on(
+            async {
+                let mut control_conn = service.get_control_auth_conn(Some(handler)).await.unwrap();
+                assert!(matches!(
+                    control_conn.wait_bootstrap(Some(1000)).await,
+                    Err(TorErrors::BootStrapError(_))
+                ));
+                // the timed out connection may be left mid reply
+                let mut control_conn = service.get_control_auth_conn(Some(handler)).await.unwrap();
+                let status = control_conn.get_status().await.unwrap();
+                assert!(matches!(status, OwnedTorServiceBootstrapPhase::Other(_)));
+            }
+            .compat(),
+        );
+    }
+
+    #[test]
+    fn owned_node_reads_requested_listeners_only() {
+        let mock = control::mock::MockControlPort::bootstrapped()
+            .getinfo("net/listeners/dns", &["\"127.0.0.1:5400\""])
+            .reply("GETINFO net/listeners/trans", "552 Unrecognized key\r\n");
+        let mut service = mock_service(&mock);
+        service.requested_listeners = vec!["dns", "trans"];
+        let owned_node = service.into_owned_node().unwrap();
+        assert_eq!(
+            owned_node.dns_listener,
+            Some("127.0.0.1:5400".parse().unwrap())
+        );
+        // a failed lookup doesn't fail the bootstrap
+        assert_eq!(owned_node.trans_listener, None);
+        assert_eq!(owned_node.http_tunnel_listener, None);
+        assert!(!mock
+            .received_commands()
+            .contains(&String::from("GETINFO net/listeners/httptunnel")));
+    }
+
+    #[test]
+    fn mock_malformed_and_error_replies() {
+        let mock = control::mock::MockControlPort::new()
+            .reply("GETINFO status/bootstrap-phase", "250 garbage\r\n")
+            .reply("GETINFO net/listeners/dns", "552 Unrecognized key\r\n");
+        let service = mock_service(&mock);
+        (*RUNTIME).lock().unwrap().block_on(
+            async {
+                let mut control_conn = service.get_control_auth_conn(Some(handler)).await.unwrap();
+                assert!(matches!(
+                    control_conn.get_status().await,
+                    Err(TorErrors::ControlConnectionError(_))
+                ));
+                assert!(matches!(
+                    control_conn.get_listener("dns").await,
+                    Err(TorErrors::ControlConnectionError(_))
+                ));
+            }
+            .compat(),
+        );
+        let rejecting_mock = control::mock::MockControlPort::new().reject_auth();
+        let service = mock_service(&rejecting_mock);
+        (*RUNTIME).lock().unwrap().block_on(
+            async {
+                assert!(service.get_control_auth_conn(Some(handler)).await.is_err());
+            }
+            .compat(),
+        );
+    }
+
+    #[test]
+    fn create_hidden_service_with_several_ports() {
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service(&mock).into_owned_node().unwrap();
+        assert!(matches!(
+            owned_node.create_hidden_service(TorHiddenServiceParam {
+                ports: vec![],
+                ..Default::default()
+            }),
+            Err(TorErrors::HiddenServiceParamError(_))
+        ));
+        let hidden_service = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![
+                    HiddenServicePort::local(80, 8080),
+                    HiddenServicePort {
+                        hs_port: 9735,
+                        target: HiddenServiceTarget::Tcp("[::1]:9735".parse().unwrap()),
+                    },
+                    HiddenServicePort {
+                        hs_port: 50001,
+                        target: HiddenServiceTarget::Unix(String::from("/tmp/electrum.sock")),
+                    },
+                ],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(hidden_service.onion_url.to_string().ends_with(".onion:80"));
+        let commands = mock.received_commands();
+        let add_onion = commands
+            .iter()
+            .find(|c| c.starts_with("ADD_ONION"))
+            .unwrap();
+        assert!(add_onion.contains("Port=80,127.0.0.1:8080"));
+        assert!(add_onion.contains("Port=9735,[::1]:9735"));
+        assert!(add_onion.contains("Port=50001,unix:/tmp/electrum.sock"));
+        owned_node
+            .delete_hidden_service(hidden_service.onion_url.to_string().replace(":80", ""))
+            .unwrap();
+        assert!(mock
+            .received_commands()
+            .iter()
+            .any(|c| c.starts_with("DEL_ONION ")));
+    }
+
+    #[test]
+    fn manage_onion_client_credentials() {
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        let mock = control::mock::MockControlPort::bootstrapped()
+            .reply(
+                "ONION_CLIENT_AUTH_VIEW",
+                "250-ONION_CLIENT_AUTH_VIEW\r\n250-CLIENT pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd x25519:AAAA Flags=Permanent\r\n250 OK\r\n",
+            )
+            .reply("ONION_CLIENT_AUTH_REMOVE", "251 No credentials for onion\r\n")
+            .reply("ONION_CLIENT_AUTH_ADD", "250 OK\r\n");
+        let mut owned_node = mock_service(&mock).into_owned_node().unwrap();
+        let keypair = onion::client_auth::ClientAuthKeypair::generate();
+        owned_node
+            .add_onion_client_auth(onion, &keypair.private_key, true)
+            .unwrap();
+        assert!(owned_node
+            .add_onion_client_auth(onion, "not a key", true)
+            .is_err());
+        let credentials = owned_node.list_onion_client_auth().unwrap();
+        assert_eq!(credentials.len(), 1);
+        assert_eq!(credentials[0].onion, onion);
+        assert!(credentials[0].permanent);
+        owned_node.remove_onion_client_auth(onion).unwrap();
+        assert!(mock
+            .received_commands()
+            .iter()
+            .any(|c| c.starts_with("ONION_CLIENT_AUTH_ADD pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd x25519:")
+                && c.ends_with("Flags=Permanent")));
+    }
+
+    #[test]
+    fn rotates_hidden_service_keys() {
+        let base_dir = "/tmp/sifir_rs_sdk_mock_rotation";
+        let _ = fs::remove_dir_all(base_dir);
+        fs::create_dir_all(base_dir).unwrap();
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service_in(&mock, base_dir).into_owned_node().unwrap();
+        owned_node.unlock_keystore(test_keystore()).unwrap();
+        let onion_of = |service: &TorHiddenService| {
+            service
+                .onion_url
+                .to_string()
+                .replace(":80", "")
+                .replace(":81", "")
+        };
+        let registered = owned_node
+            .add_registered_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(80, 8080)],
+                max_streams: Some(4),
+                ..Default::default()
+            })
+            .unwrap();
+        let ephemeral = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(81, 8081)],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let new_key = onion::keys::generate_secret_key();
+        let (rotated, migration) = owned_node
+            .rotate_hidden_service(
+                &onion_of(&registered),
+                Some(new_key),
+                Duration::from_secs(0),
+            )
+            .unwrap();
+        assert_eq!(rotated.secret_key, new_key);
+        assert_eq!(
+            migration
+                .migrate(&registered.onion_url.to_string())
+                .unwrap(),
+            Some(rotated.onion_url.to_string().parse().unwrap())
+        );
+        let add_onion = mock
+            .received_commands()
+            .into_iter()
+            .rev()
+            .find(|c| c.starts_with("ADD_ONION"))
+            .unwrap();
+        assert!(add_onion.contains(&onion::keys::to_control_blob(&new_key)));
+        assert!(add_onion.contains(" MaxStreams=4 Port=80,127.0.0.1:8080"));
+        let (_, ephemeral_migration) = owned_node
+            .rotate_hidden_service(&onion_of(&ephemeral), None, Duration::from_secs(3600))
+            .unwrap();
+        assert!(ephemeral_migration.verify().is_ok());
+
+        // the old registered one is past its grace period, the ephemeral one is still served
+        let retired = owned_node.retire_rotated_services().unwrap();
+        assert_eq!(retired, vec![onion_of(&registered)]);
+        assert!(mock.received_commands().contains(&format!(
+            "DEL_ONION {}",
+            onion_of(&registered).replace(".onion", "")
+        )));
+        let registered_onions: Vec<String> = owned_node
+            .list_registered_hidden_services()
+            .into_iter()
+            .map(|service| service.onion)
+            .collect();
+        assert_eq!(registered_onions, vec![onion_of(&rotated)]);
+        assert!(owned_node.retire_rotated_services().unwrap().is_empty());
+        assert!(owned_node
+            .rotate_hidden_service(&onion_of(&registered), None, Duration::from_secs(0))
+            .is_err());
+        drop(owned_node);
+
+        // a rotated service whose grace period ended while down is not published again
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service_in(&mock, base_dir).into_owned_node().unwrap();
+        owned_node.unlock_keystore(test_keystore()).unwrap();
+        let second = owned_node
+            .rotate_hidden_service(&onion_of(&rotated), None, Duration::from_secs(0))
+            .unwrap()
+            .0;
+        drop(owned_node);
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service_in(&mock, base_dir).into_owned_node().unwrap();
+        owned_node.unlock_keystore(test_keystore()).unwrap();
+        let registered_onions: Vec<String> = owned_node
+            .list_registered_hidden_services()
+            .into_iter()
+            .map(|service| service.onion)
+            .collect();
+        assert_eq!(registered_onions, vec![onion_of(&second)]);
+        assert_eq!(
+            mock.received_commands()
+                .iter()
+                .filter(|c| c.starts_with("ADD_ONION"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn republishes_registered_services() {
+        let base_dir = "/tmp/sifir_rs_sdk_mock_registry";
+        let _ = fs::remove_dir_all(base_dir);
+        fs::create_dir_all(base_dir).unwrap();
+
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service_in(&mock, base_dir).into_owned_node().unwrap();
+        owned_node.unlock_keystore(test_keystore()).unwrap();
+        let registered = owned_node
+            .add_registered_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(80, 8080)],
+                max_streams: Some(4),
+                max_streams_close_circuit: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let ephemeral = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(81, 8081)],
+                ..Default::default()
+            })
+            .unwrap();
+        let services = owned_node.list_registered_hidden_services();
+        assert_eq!(services.len(), 1);
+        assert_eq!(
+            format!("{}:80", services[0].onion),
+            registered.onion_url.to_string()
+        );
+        let add_onion = mock
+            .received_commands()
+            .into_iter()
+            .find(|c| c.starts_with("ADD_ONION") && c.contains("Port=80,"))
+            .unwrap();
+        drop(owned_node);
+
+        // A new daemon publishes the registered service again, but not the ephemeral one
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service_in(&mock, base_dir).into_owned_node().unwrap();
+        owned_node.unlock_keystore(test_keystore()).unwrap();
+        let add_onions: Vec<String> = mock
+            .received_commands()
+            .into_iter()
+            .filter(|c| c.starts_with("ADD_ONION"))
+            .collect();
+        assert_eq!(add_onions, vec![add_onion]);
+        assert!(add_onions[0].contains(" Flags=MaxStreamsCloseCircuit MaxStreams=4 "));
+        assert!(!add_onions[0].contains("Port=81,"));
+        assert!(owned_node
+            .remove_registered_hidden_service(&ephemeral.onion_url.to_string().replace(":81", ""))
+            .is_err());
+        owned_node
+            .remove_registered_hidden_service(&services[0].onion)
+            .unwrap();
+        assert!(owned_node.list_registered_hidden_services().is_empty());
+        assert!(mock
+            .received_commands()
+            .iter()
+            .any(|c| c.starts_with("DEL_ONION ")));
+    }
+
+    #[test]
+    fn sealed_services_wait_for_keystore_unlock() {
+        let base_dir = "/tmp/sifir_rs_sdk_mock_keystore";
+        let _ = fs::remove_dir_all(base_dir);
+        fs::create_dir_all(base_dir).unwrap();
+        let registry_path = format!("{}/onion_services.json", base_dir);
+        let add_onions = |mock: &control::mock::MockControlPort| {
+            mock.received_commands()
+                .into_iter()
+                .filter(|c| c.starts_with("ADD_ONION"))
+                .count()
+        };
+
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service_in(&mock, base_dir).into_owned_node().unwrap();
+        let param = || TorHiddenServiceParam {
+            ports: vec![HiddenServicePort::local(81, 8081)],
+            ..Default::default()
+        };
+        // nothing is published or stored while the key would have to be written in the clear
+        assert!(matches!(
+            owned_node.add_registered_hidden_service(param()),
+            Err(TorErrors::KeystoreError(_))
+        ));
+        assert_eq!(add_onions(&mock), 0);
+        assert!(owned_node.list_registered_hidden_services().is_empty());
+        owned_node.unlock_keystore(test_keystore()).unwrap();
+        let registered = owned_node.add_registered_hidden_service(param()).unwrap();
+        let stored = fs::read_to_string(&registry_path).unwrap();
+        assert!(!stored.contains(&base64::encode(&registered.secret_key[..])));
+        let onion = registered.onion_url.to_string().replace(":81", "");
+        assert_eq!(
+            owned_node.export_hidden_service_key(&onion).unwrap(),
+            registered.secret_key
+        );
+        drop(owned_node);
+
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service_in(&mock, base_dir).into_owned_node().unwrap();
+        assert_eq!(add_onions(&mock), 0);
+        assert!(owned_node
+            .unlock_keystore(Box::new(onion::keystore::WrappingKey::new([6u8; 32])))
+            .is_err());
+        assert_eq!(add_onions(&mock), 0);
+        owned_node.unlock_keystore(test_keystore()).unwrap();
+        assert_eq!(add_onions(&mock), 1);
+        assert_eq!(
+            owned_node.export_hidden_service_key(&onion).unwrap(),
+            registered.secret_key
+        );
+    }
+
+    #[test]
+    fn lists_current_and_detached_onions() {
+        let key = TorSecretKeyV3::generate();
+        let service_id = key
+            .public()
+            .get_onion_address()
+            .get_address_without_dot_onion();
+        let mock = control::mock::MockControlPort::bootstrapped()
+            .reply(
+                "GETINFO onions/current",
+                "551 No onion services of the specified type.\r\n",
+            )
+            .reply(
+                "GETINFO onions/detached",
+                &format!(
+                    "250+onions/detached=\r\n{}\r\nkciybn4d4vuqvobdl2kdp3r2rudqbqvsymqwg4jomzft6m6gaibaf6yd\r\n.\r\n250 OK\r\n",
+                    service_id
+                ),
+            );
+        let mut owned_node = mock_service(&mock).into_owned_node().unwrap();
+        owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(80, 8080)],
+                secret_key: Some(key.as_bytes()),
+                detached: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(mock
+            .received_commands()
+            .iter()
+            .any(|c| c.starts_with("ADD_ONION") && c.contains(" Flags=Detach ")));
+
+        let onions = owned_node.list_onions().unwrap();
+        assert_eq!(onions.len(), 2);
+        assert_eq!(onions[0].onion, format!("{}.onion", service_id));
+        assert!(onions[0].detached && onions[0].hosted && !onions[0].registered);
+        assert_eq!(onions[0].ports, vec![HiddenServicePort::local(80, 8080)]);
+        assert!(onions[1].detached && !onions[1].hosted);
+        assert!(onions[1].ports.is_empty());
+    }
+
+    #[test]
+    fn waits_for_descriptor_uploads() {
+        let key = TorSecretKeyV3::generate();
+        let service_id = key
+            .public()
+            .get_onion_address()
+            .get_address_without_dot_onion();
+        let mock = std::sync::Arc::new(control::mock::MockControlPort::bootstrapped());
+        let mut owned_node = mock_service(&mock).into_owned_node().unwrap();
+        let param = |key: &TorSecretKeyV3| TorHiddenServiceParam {
+            ports: vec![HiddenServicePort::local(80, 8080)],
+            secret_key: Some(key.as_bytes()),
+            ..Default::default()
+        };
+
+        // Nothing uploaded, the service is removed once we give up
+        let timed_out =
+            owned_node.create_hidden_service_and_wait(param(&key), Duration::from_millis(300));
+        assert!(matches!(timed_out, Err(TorErrors::TimeoutError(_))));
+        assert!(mock
+            .received_commands()
+            .contains(&format!("DEL_ONION {}", service_id)));
+
+        let uploader_mock = mock.clone();
+        let uploader_id = service_id.clone();
+        let uploader = std::thread::spawn(move || {
+            let added = || {
+                uploader_mock
+                    .received_commands()
+                    .iter()
+                    .filter(|c| c.starts_with("ADD_ONION"))
+                    .count()
+            };
+            while added() < 2 {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            for hs_dir in &["$AAAA", "$BBBB", "$CCCC"] {
+                uploader_mock.push_event(&format!(
+                    "HS_DESC UPLOADED {} UNKNOWN {}",
+                    uploader_id, hs_dir
+                ));
+            }
+        });
+        let published = owned_node
+            .create_hidden_service_and_wait(param(&key), Duration::from_millis(5000))
+            .unwrap();
+        uploader.join().unwrap();
+        assert_eq!(
+            published.onion_url.to_string(),
+            format!("{}.onion:80", service_id)
+        );
+        assert!(mock
+            .received_commands()
+            .contains(&String::from("SETEVENTS HS_DESC")));
+    }
+
+    #[test]
+    fn probes_remote_onions() {
+        let service_id = TorSecretKeyV3::generate()
+            .public()
+            .get_onion_address()
+            .get_address_without_dot_onion();
+        let mock = std::sync::Arc::new(control::mock::MockControlPort::bootstrapped());
+        let mut owned_node = mock_service(&mock).into_owned_node().unwrap();
+        owned_node.socks_port = onion::probe::tests::fake_socks_proxy(0x00).port();
+
+        let hsdir_mock = mock.clone();
+        let hsdir_id = service_id.clone();
+        let hsdir = std::thread::spawn(move || {
+            let fetched = |count: usize| {
+                while hsdir_mock
+                    .received_commands()
+                    .iter()
+                    .filter(|c| c.starts_with("HSFETCH"))
+                    .count()
+                    < count
+                {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                }
+            };
+            fetched(1);
+            hsdir_mock.push_event(&format!(
+                "HS_DESC FAILED {} NO_AUTH $AAAA DESCID REASON=NOT_FOUND",
+                hsdir_id
+            ));
+            fetched(2);
+            hsdir_mock.push_event(&format!(
+                "HS_DESC RECEIVED {} NO_AUTH $AAAA DESCID",
+                hsdir_id
+            ));
+        });
+        let onion = format!("{}.onion", service_id);
+        let not_found = owned_node
+            .probe_onion(&onion, Some(80), Duration::from_millis(5000))
+            .unwrap();
+        assert_eq!(not_found.outcome, ProbeOutcome::DescriptorNotFound);
+        assert_eq!(not_found.reason, Some(String::from("NOT_FOUND")));
+        assert_eq!(not_found.connect_ms, None);
+
+        let reachable = owned_node
+            .probe_onion(&format!("{}:80", onion), None, Duration::from_millis(5000))
+            .unwrap();
+        hsdir.join().unwrap();
+        assert_eq!(reachable.outcome, ProbeOutcome::Reachable);
+        assert_eq!(reachable.onion, format!("{}:80", onion));
+        assert!(reachable.descriptor_ms.is_some() && reachable.connect_ms.is_some());
+        assert!(mock
+            .received_commands()
+            .contains(&format!("HSFETCH {}", service_id)));
+
+        let timed_out = owned_node
+            .probe_onion(&onion, None, Duration::from_millis(300))
+            .unwrap();
+        assert_eq!(timed_out.outcome, ProbeOutcome::TimedOut);
+        assert!(owned_node
+            .probe_onion("expyuzz4wqqyqhjn.onion", None, Duration::from_millis(300))
+            .is_err());
+    }
+
+    #[test]
+    fn onion_only_service_rejects_clearnet_streams() {
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut service = mock_service(&mock);
+        service.onion_only = true;
+        let owned_node = service.into_owned_node().unwrap();
+        assert!(matches!(
+            owned_node.tcp_stream(String::from("electrum.blockstream.info:50001"), 1000),
+            Err(TorErrors::NonOnionTargetError(_))
+        ));
+        assert!(matches!(
+            owned_node.tcp_stream(
+                String::from("kciybn4d4vuqvobdl2kdp3r2rudqbqvsymqwg4jomzft6m6gaibaf6yd.onion"),
+                1000
+            ),
+            Err(TorErrors::OnionAddressError(_))
+        ));
+    }
+
+    #[test]
+    fn runs_non_anonymous_server() {
+        let server = |param: TorServiceParam| {
+            TorService::new(TorServiceParam {
+                data_dir: String::from("/tmp/sifir_rs_sdk_non_anonymous"),
+                non_anonymous_server: Some(NonAnonymousServer::opt_in()),
+                ..param
+            })
+        };
+        // Tor would refuse these, caught before starting it
+        for param in vec![
+            TorServiceParam {
+                socks_port: Some(19051),
+                ..Default::default()
+            },
+            TorServiceParam {
+                dns_port: Some(5400),
+                ..Default::default()
+            },
+            TorServiceParam {
+                onion_only: true,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                server(param),
+                Err(TorErrors::NonAnonymousServerError(_))
+            ));
+        }
+        let from_json: TorServiceParam =
+            serde_json::from_str(r#"{"data_dir":"/tmp","non_anonymous_server":{}}"#).unwrap();
+        assert!(from_json.non_anonymous_server.is_none());
+
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut service = mock_service(&mock);
+        service.socks_port = 0;
+        service.non_anonymous = true;
+        let mut owned_node = service.into_owned_node().unwrap();
+        owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(80, 8080)],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(mock
+            .received_commands()
+            .iter()
+            .any(|c| c.starts_with("ADD_ONION") && c.contains(" Flags=NonAnonymous ")));
+        assert!(matches!(
+            owned_node.probe_onion(
+                "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+                Some(80),
+                Duration::from_millis(100)
+            ),
+            Err(TorErrors::NonAnonymousServerError(_))
+        ));
+        struct Ignored;
+        impl HealthObserver for Ignored {
+            fn on_health_change(&self, _: &onion::health::ServiceHealth) {}
+        }
+        assert!(owned_node
+            .start_health_monitor(HealthMonitorParam::default(), Box::new(Ignored))
+            .is_err());
+    }
+
+    struct HealthChanges(std::sync::mpsc::Sender<onion::health::ServiceHealth>);
+    impl HealthObserver for HealthChanges {
+        fn on_health_change(&self, health: &onion::health::ServiceHealth) {
+            let _ = self.0.send(health.clone());
+        }
+    }
+
+    #[test]
+    fn republishes_unreachable_hosted_onions() {
+        use onion::health::HealthStatus;
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut owned_node = mock_service(&mock).into_owned_node().unwrap();
+        // introduction fails
+        owned_node.socks_port = onion::probe::tests::fake_socks_proxy(0xF2).port();
+        let hidden_service = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(80, 8080)],
+                ..Default::default()
+            })
+            .unwrap();
+        let service_id = hidden_service
+            .onion_url
+            .to_string()
+            .replace(".onion:80", "");
+
+        let (sender, changes) = std::sync::mpsc::channel();
+        let mut monitor = owned_node
+            .start_health_monitor(
+                HealthMonitorParam {
+                    interval_ms: 20,
+                    timeout_ms: 1000,
+                    failures_before_republish: 2,
+                    history_len: 4,
+                },
+                Box::new(HealthChanges(sender)),
+            )
+            .unwrap();
+        let wait = || changes.recv_timeout(Duration::from_secs(5)).unwrap();
+        let down = wait();
+        assert_eq!(down.onion, format!("{}.onion", service_id));
+        assert_eq!(down.status, HealthStatus::Unreachable);
+        assert_eq!(down.history[0].outcome, ProbeOutcome::IntroFailed);
+        let republished = wait();
+        assert_eq!(republished.status, HealthStatus::Republished);
+        assert_eq!(republished.republished, 1);
+        assert_eq!(republished.history.len(), 2);
+        monitor.stop();
+
+        let commands = mock.received_commands();
+        let deleted = commands
+            .iter()
+            .position(|c| c == &format!("DEL_ONION {}", service_id))
+            .unwrap();
+        assert!(commands[deleted + 1..]
+            .iter()
+            .any(|c| c.starts_with("ADD_ONION")));
+        assert_eq!(monitor.report()[0].republished, 1);
+        // still hosted under the same key
+        assert!(owned_node.hosted_service(&service_id).is_ok());
+    }
+
+    #[test]
+    fn republishes_onions_a_failed_republish_removed() {
+        use onion::health::HealthStatus;
+        // Tor no longer knows the service, DEL_ONION fails
+        let mock = control::mock::MockControlPort::bootstrapped()
+            .reply("DEL_ONION", "552 Unknown Onion Service id\r\n");
+        let mut owned_node = mock_service(&mock).into_owned_node().unwrap();
+        owned_node.socks_port = onion::probe::tests::fake_socks_proxy(0xF2).port();
+        owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(80, 8080)],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let (sender, changes) = std::sync::mpsc::channel();
+        let mut monitor = owned_node
+            .start_health_monitor(
+                HealthMonitorParam {
+                    interval_ms: 20,
+                    timeout_ms: 1000,
+                    failures_before_republish: 1,
+                    history_len: 4,
+                },
+                Box::new(HealthChanges(sender)),
+            )
+            .unwrap();
+        let republished = changes.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(republished.status, HealthStatus::Republished);
+        monitor.stop();
+        let added = mock
+            .received_commands()
+            .iter()
+            .filter(|c| c.starts_with("ADD_ONION"))
+            .count();
+        assert!(added >= 2);
+    }
+
+    #[test]
+    fn finds_disk_hidden_services() {
+        let mock = control::mock::MockControlPort::bootstrapped();
+        let mut service = mock_service(&mock);
+        let payments = DiskHiddenService {
+            name: String::from("payments"),
+            ports: vec![HiddenServicePort::local(80, 8080)],
+            intro_dos_defense: None,
+        };
+        let secret_key = onion::keys::generate_secret_key();
+        onion::keys::write_hidden_service_dir(
+            &secret_key,
+            &payments.dir(&service.base_dir).unwrap(),
+        )
+        .unwrap();
+        service.disk_hidden_services = vec![payments];
+        let owned_node = service.into_owned_node().unwrap();
+        assert_eq!(
+            owned_node.disk_hidden_service("payments").unwrap(),
+            onion::keys::onion_address(&secret_key).unwrap()
+        );
+        assert!(matches!(
+            owned_node.disk_hidden_service("wallet"),
+            Err(TorErrors::HiddenServiceParamError(_))
+        ));
+    }
+
+    #[test]
+    fn parses_listeners() {
+        assert_eq!(parse_listener(""), None);
+        assert_eq!(
+            parse_listener("\"127.0.0.1:5400\" \"[::1]:5400\""),
+            Some("127.0.0.1:5400".parse().unwrap())
+        );
+    }
+
     #[test]
     #[serial(tor)]
     fn from_param_and_await_boostrap() {
+        let test_network = testnet::shared_network_param();
         (*RUNTIME).lock().unwrap().block_on(
             async {
                 let service: TorService = TorServiceParam {
                     socks_port: Some(19051),
                     data_dir: String::from("/tmp/torlib2"),
-                    bootstrap_timeout_ms: Some(45000),
+                    bootstrap_timeout_ms: Some(180000),
+                    test_network: Some(test_network),
+                    ..Default::default()
                 }
                 .try_into()
                 .unwrap();
@@ -513,7 +2186,7 @@ mod tests {
                     .await
                     .unwrap();
                 let bootsraped = control_conn
-                    .wait_bootstrap(Some(20000))
+                    .wait_bootstrap(Some(180000))
                     .compat()
                     .await
                     .unwrap();
@@ -535,6 +2208,7 @@ mod tests {
                     socks_port: Some(19051),
                     data_dir: String::from("/tmp/torlib2"),
                     bootstrap_timeout_ms: Some(1000),
+                    ..Default::default()
                 }
                 .try_into()
                 .unwrap();
@@ -555,21 +2229,36 @@ mod tests {
         let service: TorService = TorServiceParam {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/torlib2"),
-            bootstrap_timeout_ms: Some(45000),
+            bootstrap_timeout_ms: Some(180000),
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
         let client = utils::get_proxied_client(service.socks_port).unwrap();
 
         let mut owned_node = service.into_owned_node().unwrap();
+        let service_key = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(20013, 20002)],
+                ..Default::default()
+            })
+            .unwrap();
+        let _handle = (*RUNTIME).lock().unwrap().spawn(async {
+            let listener = TcpListener::bind("127.0.0.1:20002").unwrap();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let response = "HTTP/1.1 200 OK\r\n\r\n";
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.flush().unwrap();
+            }
+        });
 
+        let onion_url =
+            utils::reqwest::Url::parse(&format!("http://{}", service_key.onion_url)).unwrap();
         (*RUNTIME).lock().unwrap().block_on(
             async {
-                let resp = client
-                    .get("http://keybase5wmilwokqirssclfnsqrjdsi7jdir5wy7y7iu3tanwmtp6oid.onion")
-                    .send()
-                    .await
-                    .unwrap();
+                let resp = client.get(onion_url).send().await.unwrap();
                 assert_eq!(resp.status(), 200);
             }
             .compat(),
@@ -585,6 +2274,7 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk/"),
             bootstrap_timeout_ms: Some(30000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -597,7 +2287,9 @@ mod tests {
         let service: TorService = TorServiceParam {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk"),
-            bootstrap_timeout_ms: Some(45000),
+            bootstrap_timeout_ms: Some(180000),
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -606,13 +2298,145 @@ mod tests {
         assert!(matches!(status, OwnedTorServiceBootstrapPhase::Done));
         owned_node.shutdown().unwrap();
     }
+
+    #[test]
+    #[serial(tor)]
+    fn serves_disk_hidden_service_with_intro_dos_defense() {
+        let data_dir = "/tmp/sifir_rs_sdk_disk_services";
+        let _ = fs::remove_dir_all(data_dir);
+        let service: TorService = TorServiceParam {
+            socks_port: Some(19055),
+            data_dir: String::from(data_dir),
+            bootstrap_timeout_ms: Some(180000),
+            disk_hidden_services: vec![DiskHiddenService {
+                name: String::from("payments"),
+                ports: vec![HiddenServicePort::local(20017, 20007)],
+                intro_dos_defense: Some(onion::disk::IntroDoSDefense {
+                    rate_per_sec: 25,
+                    burst_per_sec: 200,
+                }),
+            }],
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let client = utils::get_proxied_client(service.socks_port).unwrap();
+        let mut owned_node = service.into_owned_node().unwrap();
+        let onion = owned_node.disk_hidden_service("payments").unwrap();
+        assert!(owned_node.disk_hidden_service("wallet").is_err());
+        let _handle = (*RUNTIME).lock().unwrap().spawn(async {
+            let listener = TcpListener::bind("127.0.0.1:20007").unwrap();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let response = "HTTP/1.1 200 OK\r\n\r\n";
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.flush().unwrap();
+            }
+        });
+
+        let onion_url = utils::reqwest::Url::parse(&format!("http://{}:20017", onion)).unwrap();
+        (*RUNTIME).lock().unwrap().block_on(
+            async {
+                let resp = client.get(onion_url).send().await.unwrap();
+                assert_eq!(resp.status(), 200);
+            }
+            .compat(),
+        );
+        owned_node.shutdown().unwrap();
+
+        // Tor kept the key, the service comes back with the same onion
+        let mut restarted: OwnedTorService = TorServiceParam {
+            socks_port: Some(19055),
+            data_dir: String::from(data_dir),
+            bootstrap_timeout_ms: Some(180000),
+            disk_hidden_services: vec![DiskHiddenService {
+                name: String::from("payments"),
+                ports: vec![HiddenServicePort::local(20017, 20007)],
+                intro_dos_defense: None,
+            }],
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        assert_eq!(restarted.disk_hidden_service("payments").unwrap(), onion);
+        restarted.shutdown().unwrap();
+    }
+    #[test]
+    #[serial(tor)]
+    fn create_hidden_service_on_local_network() {
+        // Runs its own network, needs the tor and tor-gencert binaries but no internet access
+        let network =
+            testnet::LocalTorNetwork::start("/tmp/sifir_rs_sdk_testnet_own", 3, 3, 26000).unwrap();
+        let service: TorService = TorServiceParam {
+            socks_port: Some(19055),
+            data_dir: String::from("/tmp/sifir_rs_sdk_testnet_client"),
+            bootstrap_timeout_ms: Some(180000),
+            test_network: Some(network.test_network_param()),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let client = utils::get_proxied_client(service.socks_port).unwrap();
+        let mut owned_node = service.into_owned_node().unwrap();
+        let service_key = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(20012, 20001)],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let handle = (*RUNTIME).lock().unwrap().spawn(async {
+            let listener = TcpListener::bind("127.0.0.1:20001").unwrap();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let response = "HTTP/1.1 200 OK\r\n\r\n";
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.flush().unwrap();
+            }
+        });
+
+        let onion_url =
+            utils::reqwest::Url::parse(&format!("http://{}", service_key.onion_url)).unwrap();
+        (*RUNTIME).lock().unwrap().block_on(
+            async {
+                let resp = client.get(onion_url).send().await.unwrap();
+                assert_eq!(resp.status(), 200);
+            }
+            .compat(),
+        );
+        owned_node.shutdown().unwrap();
+        drop(network);
+    }
+
+    #[test]
+    #[serial(tor)]
+    fn resolve_through_tor() {
+        let mut owned_node: OwnedTorService = TorServiceParam {
+            socks_port: Some(19054),
+            data_dir: String::from("/tmp/sifir_rs_sdk"),
+            bootstrap_timeout_ms: Some(180000),
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        // Local network exits only reach loopback, TestingTorNetwork lets clients resolve it
+        let resolution = owned_node.resolve("localhost").unwrap();
+        assert!(matches!(resolution.address, dns::ResolvedAddress::Ip(_)));
+        assert!(owned_node.resolve("localhost SIGNAL").is_err());
+        owned_node.shutdown().unwrap();
+    }
     #[test]
     #[serial(tor)]
     fn create_hidden_service() {
         let service: TorService = TorServiceParam {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk"),
-            bootstrap_timeout_ms: Some(45000),
+            bootstrap_timeout_ms: Some(180000),
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -620,9 +2444,8 @@ mod tests {
         let mut owned_node = service.into_owned_node().unwrap();
         let service_key = owned_node
             .create_hidden_service(TorHiddenServiceParam {
-                to_port: 20000,
-                hs_port: 20011,
-                secret_key: None,
+                ports: vec![HiddenServicePort::local(20011, 20000)],
+                ..Default::default()
             })
             .unwrap();
         assert!(service_key.onion_url.to_string().contains(".onion"));
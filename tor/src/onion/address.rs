@@ -0,0 +1,185 @@
+use crate::TorErrors;
+use ed25519_dalek::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+const SERVICE_ID_LEN: usize = 56;
+/// v2 service ids, Tor dropped them in 0.4.6
+const V2_SERVICE_ID_LEN: usize = 16;
+const VERSION: u8 = 3;
+
+/// A v3 onion address, optionally with a port, as "<service id>.onion[:port]".
+/// Parsing checks the version byte and the checksum so a mistyped address is caught before
+/// Tor spends a descriptor fetch timeout on it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct OnionAddress {
+    public_key: [u8; 32],
+    port: Option<u16>,
+}
+
+impl OnionAddress {
+    pub fn from_public_key(public_key: [u8; 32]) -> Self {
+        OnionAddress {
+            public_key,
+            port: None,
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// ed25519 public key of the service, the one its descriptors are signed with
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Checks an ed25519 signature made with the key of this onion, see `keys::sign`
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let signature = match Signature::try_from(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        PublicKey::from_bytes(&self.public_key)
+            .and_then(|public_key| public_key.verify_strict(message, &signature))
+            .is_ok()
+    }
+
+    /// Address without .onion and port, as the control port uses it
+    pub fn service_id(&self) -> String {
+        let mut bytes = self.public_key.to_vec();
+        bytes.extend_from_slice(&checksum(&self.public_key));
+        bytes.push(VERSION);
+        base32::encode(BASE32, &bytes).to_lowercase()
+    }
+}
+
+/// CHECKSUM = H(".onion checksum" | PUBKEY | VERSION)[:2] (rend-spec-v3 6.)
+fn checksum(public_key: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(public_key);
+    hasher.update([VERSION]);
+    let hash = hasher.finalize();
+    [hash[0], hash[1]]
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}.onion:{}", self.service_id(), port),
+            None => write!(f, "{}.onion", self.service_id()),
+        }
+    }
+}
+
+impl FromStr for OnionAddress {
+    type Err = TorErrors;
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: &str| TorErrors::OnionAddressError(format!("{}: {}", address.trim(), reason));
+        let address = address.trim();
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                Some(port.parse::<u16>().map_err(|_| invalid("invalid port"))?),
+            ),
+            None => (address, None),
+        };
+        let host = host.to_lowercase();
+        let id = host.strip_suffix(".onion").unwrap_or(&host);
+        if id.len() == V2_SERVICE_ID_LEN {
+            return Err(invalid("v2 onion addresses are no longer supported"));
+        }
+        if id.len() != SERVICE_ID_LEN {
+            return Err(invalid("expected a 56 character v3 service id"));
+        }
+        let bytes = base32::decode(BASE32, &id.to_uppercase())
+            .filter(|bytes| bytes.len() == 35)
+            .ok_or_else(|| invalid("not base32"))?;
+        if bytes[34] != VERSION {
+            return Err(invalid("not a v3 address"));
+        }
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(&bytes[..32]);
+        if bytes[32..34] != checksum(&public_key) {
+            return Err(invalid("wrong checksum"));
+        }
+        Ok(OnionAddress { public_key, port })
+    }
+}
+
+impl TryFrom<String> for OnionAddress {
+    type Error = TorErrors;
+    fn try_from(address: String) -> Result<Self, Self::Error> {
+        address.parse()
+    }
+}
+
+impl From<OnionAddress> for String {
+    fn from(address: OnionAddress) -> Self {
+        address.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use torut::onion::TorSecretKeyV3;
+
+    #[test]
+    fn parses_and_checks_onion_addresses() {
+        let id = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+        let address: OnionAddress = format!("{}.onion", id).parse().unwrap();
+        assert_eq!(address.service_id(), id);
+        assert_eq!(address.port(), None);
+        assert_eq!(address, id.parse().unwrap());
+        assert_eq!(address, id.to_uppercase().parse().unwrap());
+        let with_port: OnionAddress = format!(" {}.onion:8080 ", id).parse().unwrap();
+        assert_eq!(with_port, address.with_port(8080));
+        assert_eq!(with_port.to_string(), format!("{}.onion:8080", id));
+
+        let key = TorSecretKeyV3::generate();
+        let onion = key.public().get_onion_address().to_string();
+        let address: OnionAddress = onion.parse().unwrap();
+        assert_eq!(address.public_key(), key.public().as_bytes());
+        assert_eq!(
+            OnionAddress::from_public_key(*key.public().as_bytes()),
+            address
+        );
+        assert_eq!(address.to_string(), onion);
+
+        // last character changed: breaks the checksum or the version byte
+        let typo = format!("{}a.onion", &id[..55]);
+        assert!(typo.parse::<OnionAddress>().is_err());
+        let mut swapped: Vec<char> = id.chars().collect();
+        swapped.swap(0, 1);
+        let swapped: String = swapped.into_iter().collect();
+        assert!(swapped.parse::<OnionAddress>().is_err());
+        assert!("expyuzz4wqqyqhjn.onion".parse::<OnionAddress>().is_err());
+        assert!(format!("{}.onion:http", id)
+            .parse::<OnionAddress>()
+            .is_err());
+        assert!("example.com".parse::<OnionAddress>().is_err());
+        assert!(format!("{}1.onion", &id[..55])
+            .parse::<OnionAddress>()
+            .is_err());
+
+        let json = serde_json::to_string(&with_port).unwrap();
+        assert_eq!(json, format!("\"{}.onion:8080\"", id));
+        assert_eq!(
+            serde_json::from_str::<OnionAddress>(&json).unwrap(),
+            with_port
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use super::service_id;
+use crate::control::{reply_value, split_reply_line};
+use crate::TorErrors;
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+const DESCRIPTOR_PREFIX: &str = "descriptor:x25519:";
+
+/// x25519 keypair a client uses to access a v3 onion service with client authorization.
+/// Keys are base32 encoded the way Tor expects them in its auth files.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientAuthKeypair {
+    pub public_key: String,
+    pub private_key: String,
+}
+
+impl ClientAuthKeypair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::new(&mut OsRng);
+        let public = PublicKey::from(&secret);
+        ClientAuthKeypair {
+            public_key: base32::encode(BASE32, public.as_bytes()),
+            private_key: base32::encode(BASE32, &secret.to_bytes()),
+        }
+    }
+
+    /// Content of the service side `authorized_clients/<client>.auth` file
+    pub fn descriptor_line(&self) -> String {
+        format!("{}{}", DESCRIPTOR_PREFIX, self.public_key)
+    }
+
+    /// Content of the client side `<client>.auth_private` file for `onion`
+    pub fn auth_private_line(&self, onion: &str) -> Result<String, TorErrors> {
+        Ok(format!(
+            "{}:{}{}",
+            service_id(onion)?,
+            DESCRIPTOR_PREFIX,
+            self.private_key
+        ))
+    }
+}
+
+/// Credentials the embedded client holds for an onion service requiring client authorization.
+/// The private key itself is never handed back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnionClientAuth {
+    pub onion: String,
+    pub client_name: Option<String>,
+    /// Kept in the ClientOnionAuthDir so they survive a daemon restart
+    pub permanent: bool,
+}
+
+/// Builds ONION_CLIENT_AUTH_ADD for `onion` from a private key given either base32 encoded
+/// (optionally as a whole `.auth_private` line) or base64 encoded like the control port wants it
+pub(crate) fn onion_client_auth_add_command(
+    onion: &str,
+    private_key: &str,
+    permanent: bool,
+) -> Result<String, TorErrors> {
+    let key = private_key.trim().rsplit(':').next().unwrap_or("");
+    let bytes = match base32::decode(BASE32, &key.to_uppercase()) {
+        Some(bytes) if bytes.len() == 32 => bytes,
+        _ => match base64::decode(key) {
+            Ok(bytes) if bytes.len() == 32 => bytes,
+            _ => {
+                return Err(TorErrors::ClientAuthError(String::from(
+                    "Invalid x25519 client private key",
+                )))
+            }
+        },
+    };
+    let mut command = format!(
+        "ONION_CLIENT_AUTH_ADD {} x25519:{}",
+        service_id(onion)?,
+        base64::encode(&bytes)
+    );
+    if permanent {
+        command.push_str(" Flags=Permanent");
+    }
+    Ok(command)
+}
+
+/// Parses the CLIENT lines of an ONION_CLIENT_AUTH_VIEW reply:
+/// CLIENT HSAddress KeyType:PrivateKeyBlob [ClientName=Nickname] [Flags=FLAGS]
+pub(crate) fn parse_onion_client_auth_view(lines: &[String]) -> Vec<OnionClientAuth> {
+    lines
+        .iter()
+        .map(|line| split_reply_line(line))
+        .filter(|tokens| tokens.len() >= 3 && tokens[0] == "CLIENT")
+        .map(|tokens| OnionClientAuth {
+            onion: format!("{}.onion", tokens[1]),
+            client_name: reply_value(&tokens, "ClientName").map(String::from),
+            permanent: reply_value(&tokens, "Flags")
+                .map(|flags| flags.split(',').any(|f| f == "Permanent"))
+                .unwrap_or(false),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_tor_formatted_keys() {
+        let keypair = ClientAuthKeypair::generate();
+        assert_eq!(keypair.public_key.len(), 52);
+        assert_eq!(keypair.private_key.len(), 52);
+        assert!(keypair.descriptor_line().starts_with("descriptor:x25519:"));
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        let auth_private = keypair.auth_private_line(onion).unwrap();
+        assert!(auth_private.starts_with(
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd:descriptor:x25519:"
+        ));
+    }
+
+    #[test]
+    fn builds_and_parses_client_credentials() {
+        let keypair = ClientAuthKeypair::generate();
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        let blob = base64::encode(&base32::decode(BASE32, &keypair.private_key).unwrap()[..]);
+        let expected = format!(
+            "ONION_CLIENT_AUTH_ADD pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd x25519:{}",
+            blob
+        );
+        assert_eq!(
+            onion_client_auth_add_command(onion, &keypair.private_key, false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            onion_client_auth_add_command(onion, &blob, false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            onion_client_auth_add_command(onion, &keypair.auth_private_line(onion).unwrap(), true)
+                .unwrap(),
+            format!("{} Flags=Permanent", expected)
+        );
+        assert!(onion_client_auth_add_command(onion, "nope", false).is_err());
+
+        let view = vec![
+            String::from("ONION_CLIENT_AUTH_VIEW"),
+            format!(
+                "CLIENT pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd x25519:{} ClientName=node Flags=Permanent",
+                blob
+            ),
+            String::from("CLIENT abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx x25519:AAAA"),
+            String::from("OK"),
+        ];
+        let credentials = parse_onion_client_auth_view(&view);
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials[0].onion, onion);
+        assert_eq!(credentials[0].client_name, Some(String::from("node")));
+        assert!(credentials[0].permanent);
+        assert_eq!(credentials[1].client_name, None);
+        assert!(!credentials[1].permanent);
+    }
+}
@@ -0,0 +1,187 @@
+use super::{HiddenServiceTarget, OnionAddress};
+use crate::{HiddenServicePort, TorErrors};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const SERVICES_DIR: &str = "onion_services";
+const HOSTNAME_FILE: &str = "hostname";
+
+/// Onion service Tor publishes from a HiddenServiceDir it is configured with at startup, rather
+/// than one added with ADD_ONION. Tor generates and keeps the key in
+/// `<data_dir>/sifir_sdk/tor/onion_services/<name>`, so the onion stays the same across restarts.
+/// Needed for the options Tor only reads from a HiddenServiceDir config, like the introduction
+/// point DoS defense
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiskHiddenService {
+    /// Letters, digits, '-' and '_', names the directory of the service
+    pub name: String,
+    pub ports: Vec<HiddenServicePort>,
+    /// Rate limit introduction requests at the introduction points, None leaves it to the
+    /// network consensus (off by default)
+    #[serde(default)]
+    pub intro_dos_defense: Option<IntroDoSDefense>,
+}
+
+/// HiddenServiceEnableIntroDoSDefense token bucket: introduction points let through
+/// rate_per_sec requests a second for a service, up to burst_per_sec at once
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IntroDoSDefense {
+    pub rate_per_sec: u32,
+    pub burst_per_sec: u32,
+}
+
+impl DiskHiddenService {
+    /// HiddenServiceDir of the service under the SDK base dir
+    pub(crate) fn dir(&self, base_dir: &str) -> Result<String, TorErrors> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(TorErrors::HiddenServiceParamError(format!(
+                "Invalid service name {}",
+                self.name
+            )));
+        }
+        Ok(format!("{}/{}/{}", base_dir, SERVICES_DIR, self.name))
+    }
+
+    /// torrc lines configuring the service, the options apply to the HiddenServiceDir before them
+    pub(crate) fn torrc_lines(&self, base_dir: &str) -> Result<Vec<String>, TorErrors> {
+        if self.ports.is_empty() {
+            return Err(TorErrors::HiddenServiceParamError(String::from(
+                "No port mapping given",
+            )));
+        }
+        let mut lines = vec![format!("HiddenServiceDir {}", self.dir(base_dir)?)];
+        for port in &self.ports {
+            // Re-validate as targets can be built without going through FromStr
+            let target: HiddenServiceTarget = port.target.to_string().parse()?;
+            lines.push(format!("HiddenServicePort {} {}", port.hs_port, target));
+        }
+        if let Some(defense) = self.intro_dos_defense {
+            // Tor takes both as an int32 and refuses a burst below the rate, a zero rate would
+            // refuse every client
+            if defense.rate_per_sec == 0
+                || defense.burst_per_sec < defense.rate_per_sec
+                || defense.burst_per_sec > i32::MAX as u32
+            {
+                return Err(TorErrors::HiddenServiceParamError(format!(
+                    "Invalid intro DoS defense rate {} burst {}",
+                    defense.rate_per_sec, defense.burst_per_sec
+                )));
+            }
+            lines.push(String::from("HiddenServiceEnableIntroDoSDefense 1"));
+            lines.push(format!(
+                "HiddenServiceEnableIntroDoSRatePerSec {}",
+                defense.rate_per_sec
+            ));
+            lines.push(format!(
+                "HiddenServiceEnableIntroDoSBurstPerSec {}",
+                defense.burst_per_sec
+            ));
+        }
+        Ok(lines)
+    }
+
+    /// Creates the HiddenServiceDir, Tor refuses one others can read
+    pub(crate) fn create_dir(&self, base_dir: &str) -> Result<(), TorErrors> {
+        let dir = self.dir(base_dir)?;
+        fs::create_dir_all(&dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(())
+    }
+
+    /// Onion Tor wrote in the hostname file of the service once it started it
+    pub(crate) fn onion(&self, base_dir: &str) -> Result<OnionAddress, TorErrors> {
+        fs::read_to_string(Path::new(&self.dir(base_dir)?).join(HOSTNAME_FILE))?
+            .trim()
+            .parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::keys;
+
+    fn service(name: &str) -> DiskHiddenService {
+        DiskHiddenService {
+            name: String::from(name),
+            ports: vec![
+                HiddenServicePort::local(80, 8080),
+                HiddenServicePort {
+                    hs_port: 9735,
+                    target: HiddenServiceTarget::Unix(String::from("/tmp/ln.sock")),
+                },
+            ],
+            intro_dos_defense: None,
+        }
+    }
+
+    #[test]
+    fn builds_torrc_lines() {
+        let mut payments = service("payments");
+        assert_eq!(
+            payments.torrc_lines("/data").unwrap(),
+            vec![
+                "HiddenServiceDir /data/onion_services/payments",
+                "HiddenServicePort 80 127.0.0.1:8080",
+                "HiddenServicePort 9735 unix:/tmp/ln.sock",
+            ]
+        );
+        payments.intro_dos_defense = Some(IntroDoSDefense {
+            rate_per_sec: 25,
+            burst_per_sec: 200,
+        });
+        assert_eq!(
+            payments.torrc_lines("/data").unwrap()[3..],
+            [
+                "HiddenServiceEnableIntroDoSDefense 1",
+                "HiddenServiceEnableIntroDoSRatePerSec 25",
+                "HiddenServiceEnableIntroDoSBurstPerSec 200",
+            ]
+        );
+        for &(rate_per_sec, burst_per_sec) in [(0, 10), (25, 10), (25, u32::MAX)].iter() {
+            payments.intro_dos_defense = Some(IntroDoSDefense {
+                rate_per_sec,
+                burst_per_sec,
+            });
+            assert!(payments.torrc_lines("/data").is_err());
+        }
+        for name in ["", "../registry", "a b", "a\nSocksPort 9050"].iter() {
+            assert!(service(name).torrc_lines("/data").is_err());
+        }
+        let mut no_ports = service("payments");
+        no_ports.ports.clear();
+        assert!(no_ports.torrc_lines("/data").is_err());
+
+        let from_json: DiskHiddenService = serde_json::from_str(
+            r#"{"name":"payments","ports":[{"hs_port":80,"target":"127.0.0.1:8080"}],
+                "intro_dos_defense":{"rate_per_sec":25,"burst_per_sec":200}}"#,
+        )
+        .unwrap();
+        assert_eq!(from_json.intro_dos_defense.unwrap().burst_per_sec, 200);
+    }
+
+    #[test]
+    fn reads_onion_tor_wrote() {
+        let base_dir = "/tmp/sifir_rs_sdk_disk_hidden_service";
+        let _ = fs::remove_dir_all(base_dir);
+        let payments = service("payments");
+        payments.create_dir(base_dir).unwrap();
+        assert!(payments.onion(base_dir).is_err());
+        let secret_key = keys::generate_secret_key();
+        keys::write_hidden_service_dir(&secret_key, &payments.dir(base_dir).unwrap()).unwrap();
+        assert_eq!(
+            payments.onion(base_dir).unwrap(),
+            keys::onion_address(&secret_key).unwrap()
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Background monitor of the onion services we host: every interval each one is connected to
+//! through our own SocksPort, like a client would, and a service failing several checks in a row
+//! is republished (DEL_ONION + ADD_ONION) to get fresh introduction points and descriptors.
+use crate::control::ControlConn;
+use crate::onion::probe::{socks_connect, socks_reply_outcome, ProbeOutcome};
+use crate::{onion_command_on, HostedService, TorErrors};
+use logger::log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct HealthMonitorParam {
+    pub interval_ms: u64,
+    /// Time given to each connection, reaching a service can take a while on a fresh circuit
+    pub timeout_ms: u64,
+    /// Failed checks in a row before the service is republished
+    pub failures_before_republish: u32,
+    /// Checks kept per service
+    pub history_len: usize,
+}
+
+impl Default for HealthMonitorParam {
+    fn default() -> Self {
+        HealthMonitorParam {
+            interval_ms: 5 * 60 * 1000,
+            timeout_ms: 60 * 1000,
+            failures_before_republish: 3,
+            history_len: 24,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HealthStatus {
+    /// Not checked yet
+    Unknown,
+    Reachable,
+    Unreachable,
+    /// Just deleted and added back after failing failures_before_republish checks
+    Republished,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    /// Unix time in ms
+    pub at_ms: u64,
+    pub outcome: ProbeOutcome,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceHealth {
+    pub onion: String,
+    pub status: HealthStatus,
+    pub consecutive_failures: u32,
+    pub republished: u32,
+    /// Share of the checks in history that reached the service
+    pub availability: f64,
+    /// Oldest first
+    pub history: VecDeque<HealthCheck>,
+}
+
+impl ServiceHealth {
+    fn new(onion: String) -> Self {
+        ServiceHealth {
+            onion,
+            status: HealthStatus::Unknown,
+            consecutive_failures: 0,
+            republished: 0,
+            availability: 0.0,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Records a check, returns true if the status changed
+    fn record(&mut self, check: HealthCheck, history_len: usize) -> bool {
+        let status = if is_reachable(check.outcome) {
+            self.consecutive_failures = 0;
+            HealthStatus::Reachable
+        } else {
+            self.consecutive_failures += 1;
+            HealthStatus::Unreachable
+        };
+        self.history.push_back(check);
+        while self.history.len() > history_len.max(1) {
+            self.history.pop_front();
+        }
+        let reached = self
+            .history
+            .iter()
+            .filter(|check| is_reachable(check.outcome))
+            .count();
+        self.availability = reached as f64 / self.history.len() as f64;
+        let changed = self.status != status;
+        self.status = status;
+        changed
+    }
+}
+
+/// A closed port still means Tor reached the service and only the app behind it is down, a
+/// failed client authorization that the service is up but only lets its clients in (the
+/// monitor has no credentials), republishing would not help either
+fn is_reachable(outcome: ProbeOutcome) -> bool {
+    matches!(
+        outcome,
+        ProbeOutcome::Reachable | ProbeOutcome::PortClosed | ProbeOutcome::ClientAuthFailed
+    )
+}
+
+/// Told about a service becoming reachable, unreachable or being republished
+pub trait HealthObserver: Send {
+    fn on_health_change(&self, health: &ServiceHealth);
+}
+
+/// Monitor running on its own thread, dropping it stops it
+pub struct HealthMonitor {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    health: Arc<Mutex<HashMap<String, ServiceHealth>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl HealthMonitor {
+    pub(crate) fn start(
+        socks_port: u16,
+        onion_ctl: Arc<Mutex<Option<ControlConn>>>,
+        hosted_services: Arc<Mutex<HashMap<String, HostedService>>>,
+        param: HealthMonitorParam,
+        observer: Box<dyn HealthObserver>,
+    ) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let health = Arc::new(Mutex::new(HashMap::new()));
+        let worker = MonitorWorker {
+            proxy: SocketAddr::from(([127, 0, 0, 1], socks_port)),
+            onion_ctl,
+            hosted_services,
+            param,
+            observer,
+            stopped: stopped.clone(),
+            health: health.clone(),
+        };
+        HealthMonitor {
+            stopped,
+            health,
+            worker: Some(std::thread::spawn(move || worker.run())),
+        }
+    }
+
+    /// Health of the hosted services checked so far
+    pub fn report(&self) -> Vec<ServiceHealth> {
+        let mut report: Vec<ServiceHealth> =
+            self.health.lock().unwrap().values().cloned().collect();
+        report.sort_by(|a, b| a.onion.cmp(&b.onion));
+        report
+    }
+
+    /// Stops the monitor, waiting for a running check to end
+    pub fn stop(&mut self) {
+        let (stopped, wake) = &*self.stopped;
+        *stopped.lock().unwrap() = true;
+        wake.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct MonitorWorker {
+    proxy: SocketAddr,
+    onion_ctl: Arc<Mutex<Option<ControlConn>>>,
+    hosted_services: Arc<Mutex<HashMap<String, HostedService>>>,
+    param: HealthMonitorParam,
+    observer: Box<dyn HealthObserver>,
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    health: Arc<Mutex<HashMap<String, ServiceHealth>>>,
+}
+
+impl MonitorWorker {
+    fn run(self) {
+        loop {
+            let services: Vec<(String, u16)> = self
+                .hosted_services
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(id, service)| service.ports.first().map(|p| (id.clone(), p.hs_port)))
+                .collect();
+            // forget deleted services
+            self.health
+                .lock()
+                .unwrap()
+                .retain(|id, _| services.iter().any(|(service_id, _)| service_id == id));
+            for (service_id, port) in services {
+                if self.is_stopped() {
+                    return;
+                }
+                self.check(&service_id, port);
+            }
+            let (stopped, wake) = &*self.stopped;
+            let stopped = wake
+                .wait_timeout_while(
+                    stopped.lock().unwrap(),
+                    Duration::from_millis(self.param.interval_ms),
+                    |stopped| !*stopped,
+                )
+                .unwrap()
+                .0;
+            if *stopped {
+                return;
+            }
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stopped.0.lock().unwrap()
+    }
+
+    fn check(&self, service_id: &str, port: u16) {
+        let started = Instant::now();
+        let onion = format!("{}.onion", service_id);
+        let timeout = Duration::from_millis(self.param.timeout_ms);
+        let outcome = match socks_connect(self.proxy, &onion, port, timeout) {
+            Ok(Some(code)) => socks_reply_outcome(code),
+            Ok(None) => ProbeOutcome::TimedOut,
+            Err(e) => {
+                warn!("Health check of {} failed: {:?}", onion, e);
+                ProbeOutcome::Unreachable
+            }
+        };
+        let check = HealthCheck {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_millis() as u64)
+                .unwrap_or_default(),
+            outcome,
+            latency_ms: if is_reachable(outcome) {
+                Some(started.elapsed().as_millis() as u64)
+            } else {
+                None
+            },
+        };
+        let (mut changed, failures) = {
+            let mut health = self.health.lock().unwrap();
+            let service = health
+                .entry(String::from(service_id))
+                .or_insert_with(|| ServiceHealth::new(onion.clone()));
+            let changed = service.record(check, self.param.history_len);
+            (changed, service.consecutive_failures)
+        };
+        if failures >= self.param.failures_before_republish.max(1) {
+            match self.republish(service_id) {
+                Ok(_) => {
+                    info!("Republished {} after {} failed checks", onion, failures);
+                    if let Some(service) = self.health.lock().unwrap().get_mut(service_id) {
+                        service.status = HealthStatus::Republished;
+                        service.consecutive_failures = 0;
+                        service.republished += 1;
+                    }
+                    changed = true;
+                }
+                Err(e) => warn!("Error republishing {}: {:?}", onion, e),
+            }
+        }
+        if changed {
+            let health = self.health.lock().unwrap().get(service_id).cloned();
+            if let Some(health) = health {
+                self.observer.on_health_change(&health);
+            }
+        }
+    }
+
+    fn republish(&self, service_id: &str) -> Result<(), TorErrors> {
+        // the service may have been deleted since the check
+        let service = self
+            .hosted_services
+            .lock()
+            .unwrap()
+            .get(service_id)
+            .cloned()
+            .ok_or_else(|| {
+                TorErrors::HiddenServiceParamError(format!("{} was deleted", service_id))
+            })?;
+        let add_onion = service.add_onion_command()?;
+        match onion_command_on(&self.onion_ctl, &format!("DEL_ONION {}", service_id)) {
+            // 552 unknown service: a previous attempt removed it and could not add it back
+            Ok(_) | Err(TorErrors::ControlCommandError(552, _)) => {}
+            Err(e) => return Err(e),
+        }
+        if let Err(e) = onion_command_on(&self.onion_ctl, &add_onion) {
+            warn!("Error republishing {}, adding it back", service_id);
+            onion_command_on(&self.onion_ctl, &add_onion)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(outcome: ProbeOutcome) -> HealthCheck {
+        HealthCheck {
+            at_ms: 0,
+            outcome,
+            latency_ms: None,
+        }
+    }
+
+    #[test]
+    fn records_service_health() {
+        let mut health = ServiceHealth::new(String::from("abc.onion"));
+        assert!(health.record(check(ProbeOutcome::Reachable), 3));
+        assert!(!health.record(check(ProbeOutcome::PortClosed), 3));
+        assert!(health.record(check(ProbeOutcome::IntroFailed), 3));
+        assert_eq!(health.status, HealthStatus::Unreachable);
+        assert!(!health.record(check(ProbeOutcome::TimedOut), 3));
+        assert_eq!(health.consecutive_failures, 2);
+        assert_eq!(health.history.len(), 3);
+        assert!((health.availability - 1.0 / 3.0).abs() < 1e-9);
+        assert!(health.record(check(ProbeOutcome::Reachable), 3));
+        assert_eq!(health.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn client_auth_failure_is_reachable() {
+        let mut health = ServiceHealth::new(String::from("abc.onion"));
+        assert!(health.record(check(ProbeOutcome::ClientAuthFailed), 3));
+        assert_eq!(health.status, HealthStatus::Reachable);
+        assert_eq!(health.consecutive_failures, 0);
+        assert!((health.availability - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::control::{reply_value, split_reply_line};
+use std::collections::HashSet;
+use std::fmt;
+
+/// HSDirs that must accept a descriptor of a new service before we consider it reachable.
+/// Tor uploads each of the two descriptors of a v3 service to 8 HSDirs and clients fetch from
+/// a few of those, so a single upload often isn't enough for the first connection to work.
+pub(crate) const MIN_HSDIR_UPLOADS: usize = 3;
+
+/// Tracks the HS_DESC UPLOAD / UPLOADED / FAILED events of one service
+pub(crate) struct DescriptorUploads {
+    service_id: String,
+    started: HashSet<String>,
+    uploaded: HashSet<String>,
+    failed: Vec<String>,
+}
+
+impl DescriptorUploads {
+    pub(crate) fn new(service_id: &str) -> Self {
+        DescriptorUploads {
+            service_id: String::from(service_id),
+            started: HashSet::new(),
+            uploaded: HashSet::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    /// Records an event, returns true once MIN_HSDIR_UPLOADS HSDirs accepted a descriptor.
+    /// 650 HS_DESC Action HSAddress AuthType HsDir [DescriptorID] [REASON=Reason] ...
+    pub(crate) fn record(&mut self, event: &[String]) -> bool {
+        let tokens = match event.first() {
+            Some(line) => split_reply_line(line),
+            None => return false,
+        };
+        if tokens.len() < 5 || tokens[0] != "HS_DESC" || tokens[2] != self.service_id {
+            return false;
+        }
+        let hs_dir = tokens[4].clone();
+        match tokens[1].as_str() {
+            "UPLOAD" => {
+                self.started.insert(hs_dir);
+            }
+            "UPLOADED" => {
+                self.uploaded.insert(hs_dir);
+            }
+            "FAILED" => {
+                let reason = reply_value(&tokens, "REASON").unwrap_or("UNKNOWN");
+                self.failed.push(format!("{} {}", hs_dir, reason));
+            }
+            _ => {}
+        }
+        self.uploaded.len() >= MIN_HSDIR_UPLOADS
+    }
+}
+
+impl fmt::Display for DescriptorUploads {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} descriptor uploads started, {} accepted, {} failed",
+            self.started.len(),
+            self.uploaded.len(),
+            self.failed.len()
+        )?;
+        if !self.failed.is_empty() {
+            write!(f, " ({})", self.failed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Follows the HS_DESC and HS_DESC_CONTENT events of an HSFETCH of one service
+pub(crate) struct DescriptorFetch {
+    service_id: String,
+}
+
+impl DescriptorFetch {
+    pub(crate) fn new(service_id: &str) -> Self {
+        DescriptorFetch {
+            service_id: String::from(service_id),
+        }
+    }
+
+    /// Returns Some once the fetch is over, Ok if the descriptor was received or the failure reason.
+    /// 650 HS_DESC RECEIVED|FAILED HSAddress AuthType HsDir [DescriptorID] [REASON=Reason]
+    /// 650+HS_DESC_CONTENT HSAddress DescId HsDir, with an empty data block when nothing was found
+    pub(crate) fn record(&self, event: &[String]) -> Option<Result<(), String>> {
+        let line = event.first()?;
+        let (header, content) = match line.split_once("\r\n") {
+            Some((header, content)) => (header, content.trim()),
+            None => (line.as_str(), ""),
+        };
+        let tokens = split_reply_line(header);
+        match tokens.first().map(String::as_str) {
+            Some("HS_DESC") if tokens.len() >= 5 && tokens[2] == self.service_id => {
+                match tokens[1].as_str() {
+                    "RECEIVED" => Some(Ok(())),
+                    "FAILED" => Some(Err(String::from(
+                        reply_value(&tokens, "REASON").unwrap_or("UNKNOWN"),
+                    ))),
+                    _ => None,
+                }
+            }
+            Some("HS_DESC_CONTENT") if tokens.len() >= 2 && tokens[1] == self.service_id => {
+                if content.is_empty() || content == "." {
+                    Some(Err(String::from("NOT_FOUND")))
+                } else {
+                    Some(Ok(()))
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(line: &str) -> Vec<String> {
+        vec![String::from(line)]
+    }
+
+    #[test]
+    fn counts_accepted_uploads() {
+        let mut uploads = DescriptorUploads::new("abc234");
+        assert!(!uploads.record(&event("HS_DESC UPLOAD abc234 UNKNOWN $AAAA~relay1 DESCID")));
+        assert!(!uploads.record(&event("HS_DESC UPLOADED abc234 UNKNOWN $AAAA~relay1")));
+        // the same HSDir twice and other services don't count
+        assert!(!uploads.record(&event("HS_DESC UPLOADED abc234 UNKNOWN $AAAA~relay1")));
+        assert!(!uploads.record(&event("HS_DESC UPLOADED other567 UNKNOWN $BBBB")));
+        assert!(!uploads.record(&event(
+            "HS_DESC FAILED abc234 UNKNOWN $CCCC DESCID REASON=UPLOAD_REJECTED"
+        )));
+        assert!(!uploads.record(&event("HS_DESC UPLOADED abc234 UNKNOWN $DDDD")));
+        assert_eq!(
+            uploads.to_string(),
+            "1 descriptor uploads started, 2 accepted, 1 failed ($CCCC UPLOAD_REJECTED)"
+        );
+        assert!(uploads.record(&event("HS_DESC UPLOADED abc234 UNKNOWN $EEEE")));
+        assert!(!uploads.record(&[]));
+    }
+
+    #[test]
+    fn follows_descriptor_fetches() {
+        let fetch = DescriptorFetch::new("abc234");
+        assert_eq!(
+            fetch.record(&event("HS_DESC REQUESTED abc234 NO_AUTH $AAAA DESCID")),
+            None
+        );
+        assert_eq!(
+            fetch.record(&event("HS_DESC RECEIVED other567 NO_AUTH $AAAA")),
+            None
+        );
+        assert_eq!(
+            fetch.record(&event("HS_DESC RECEIVED abc234 NO_AUTH $AAAA DESCID")),
+            Some(Ok(()))
+        );
+        assert_eq!(
+            fetch.record(&event(
+                "HS_DESC FAILED abc234 NO_AUTH $AAAA DESCID REASON=NOT_FOUND"
+            )),
+            Some(Err(String::from("NOT_FOUND")))
+        );
+        assert_eq!(
+            fetch.record(&event(
+                "HS_DESC_CONTENT abc234 DESCID $AAAA\r\nhs-descriptor 3\r\n."
+            )),
+            Some(Ok(()))
+        );
+        assert_eq!(
+            fetch.record(&event("HS_DESC_CONTENT abc234 DESCID $AAAA\r\n")),
+            Some(Err(String::from("NOT_FOUND")))
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Conversions between the 64 byte expanded ed25519 secret key the SDK uses and the formats Tor
+//! stores or takes onion keys in, to move a service between a HiddenServiceDir and the SDK.
+//! Also the operations on onion keys that don't need a running daemon.
+use crate::onion::OnionAddress;
+use crate::TorErrors;
+use ed25519_dalek::{ExpandedSecretKey, PublicKey};
+use std::fs;
+use std::path::Path;
+use torut::onion::TorSecretKeyV3;
+
+const SECRET_KEY_HEADER: &[u8; 32] = b"== ed25519v1-secret: type0 ==\0\0\0";
+const PUBLIC_KEY_HEADER: &[u8; 32] = b"== ed25519v1-public: type0 ==\0\0\0";
+const CONTROL_BLOB_PREFIX: &str = "ED25519-V3:";
+const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+const HOSTNAME_FILE: &str = "hostname";
+
+/// New random onion secret key
+pub fn generate_secret_key() -> [u8; 64] {
+    TorSecretKeyV3::generate().as_bytes()
+}
+
+/// Onion address of the service hosted with secret_key
+pub fn onion_address(secret_key: &[u8; 64]) -> Result<OnionAddress, TorErrors> {
+    check_expanded_key(secret_key)?;
+    Ok(OnionAddress::from_public_key(
+        *TorSecretKeyV3::from(*secret_key).public().as_bytes(),
+    ))
+}
+
+/// ed25519 signature of message with the onion key, which anyone knowing the onion address can
+/// check with `OnionAddress::verify`
+pub fn sign(secret_key: &[u8; 64], message: &[u8]) -> Result<[u8; 64], TorErrors> {
+    check_expanded_key(secret_key)?;
+    let expanded = ExpandedSecretKey::from_bytes(&secret_key[..])
+        .map_err(|e| TorErrors::OnionKeyError(format!("{}", e)))?;
+    let public_key = PublicKey::from(&expanded);
+    Ok(expanded.sign(message, &public_key).to_bytes())
+}
+
+/// Content of a HiddenServiceDir `hs_ed25519_secret_key` file
+pub fn to_secret_key_file(secret_key: &[u8; 64]) -> Vec<u8> {
+    let mut file = SECRET_KEY_HEADER.to_vec();
+    file.extend_from_slice(&secret_key[..]);
+    file
+}
+
+pub fn from_secret_key_file(file: &[u8]) -> Result<[u8; 64], TorErrors> {
+    if file.len() != 96 || &file[..32] != SECRET_KEY_HEADER {
+        return Err(TorErrors::OnionKeyError(String::from(
+            "Not an hs_ed25519_secret_key file",
+        )));
+    }
+    let mut secret_key = [0u8; 64];
+    secret_key.copy_from_slice(&file[32..]);
+    check_expanded_key(&secret_key)?;
+    Ok(secret_key)
+}
+
+/// Content of a HiddenServiceDir `hs_ed25519_public_key` file
+pub fn to_public_key_file(secret_key: &[u8; 64]) -> Vec<u8> {
+    let mut file = PUBLIC_KEY_HEADER.to_vec();
+    file.extend_from_slice(TorSecretKeyV3::from(*secret_key).public().as_bytes());
+    file
+}
+
+/// Content of a HiddenServiceDir `hostname` file
+pub fn to_hostname(secret_key: &[u8; 64]) -> String {
+    format!(
+        "{}\n",
+        TorSecretKeyV3::from(*secret_key)
+            .public()
+            .get_onion_address()
+    )
+}
+
+/// Key blob ADD_ONION takes and returns with ServiceID: `ED25519-V3:<base64>`
+pub fn to_control_blob(secret_key: &[u8; 64]) -> String {
+    format!("{}{}", CONTROL_BLOB_PREFIX, base64::encode(&secret_key[..]))
+}
+
+/// Parses a key given either as an `ED25519-V3:` blob or as the bare base64 of the key
+pub fn from_control_blob(blob: &str) -> Result<[u8; 64], TorErrors> {
+    let blob = blob.trim();
+    let encoded = blob.strip_prefix(CONTROL_BLOB_PREFIX).unwrap_or(blob);
+    let mut secret_key = [0u8; 64];
+    match base64::decode_config_slice(encoded, base64::STANDARD, &mut secret_key) {
+        Ok(64) => {
+            check_expanded_key(&secret_key)?;
+            Ok(secret_key)
+        }
+        _ => Err(TorErrors::OnionKeyError(String::from(
+            "Expected the base64 of a 64 byte ed25519 expanded secret key",
+        ))),
+    }
+}
+
+/// Writes the key files and hostname of the service to dir the way Tor does in a HiddenServiceDir,
+/// which can then be used by a standalone tor
+pub fn write_hidden_service_dir(secret_key: &[u8; 64], dir: &str) -> Result<(), TorErrors> {
+    fs::create_dir_all(dir)?;
+    let files = [
+        (SECRET_KEY_FILE, to_secret_key_file(secret_key)),
+        (PUBLIC_KEY_FILE, to_public_key_file(secret_key)),
+        (HOSTNAME_FILE, to_hostname(secret_key).into_bytes()),
+    ];
+    for (name, content) in files.iter() {
+        let path = Path::new(dir).join(name);
+        fs::write(&path, content)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    // Tor refuses to use a HiddenServiceDir others can read
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Reads the secret key of a HiddenServiceDir, making sure it matches the public key and hostname
+/// files when they are there
+pub fn read_hidden_service_dir(dir: &str) -> Result<[u8; 64], TorErrors> {
+    let secret_key = from_secret_key_file(&fs::read(Path::new(dir).join(SECRET_KEY_FILE))?)?;
+    let public_key_path = Path::new(dir).join(PUBLIC_KEY_FILE);
+    if public_key_path.exists() && fs::read(public_key_path)? != to_public_key_file(&secret_key) {
+        return Err(TorErrors::OnionKeyError(format!(
+            "{} does not match {}",
+            PUBLIC_KEY_FILE, SECRET_KEY_FILE
+        )));
+    }
+    let hostname_path = Path::new(dir).join(HOSTNAME_FILE);
+    if hostname_path.exists()
+        && fs::read_to_string(hostname_path)?.trim() != to_hostname(&secret_key).trim()
+    {
+        return Err(TorErrors::OnionKeyError(format!(
+            "{} does not match {}",
+            HOSTNAME_FILE, SECRET_KEY_FILE
+        )));
+    }
+    Ok(secret_key)
+}
+
+/// The first half of an expanded key is a clamped scalar, catches keys that are really seeds
+/// or garbage before Tor rejects them
+fn check_expanded_key(secret_key: &[u8; 64]) -> Result<(), TorErrors> {
+    if secret_key[0] & 7 == 0 && secret_key[31] & 0xc0 == 0x40 {
+        Ok(())
+    } else {
+        Err(TorErrors::OnionKeyError(String::from(
+            "Not an ed25519 expanded secret key",
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_tor_key_formats() {
+        let key = TorSecretKeyV3::generate();
+        let secret_key = key.as_bytes();
+        let onion = key.public().get_onion_address().to_string();
+
+        let secret_file = to_secret_key_file(&secret_key);
+        assert_eq!(secret_file.len(), 96);
+        assert!(secret_file.starts_with(b"== ed25519v1-secret: type0 =="));
+        assert_eq!(from_secret_key_file(&secret_file).unwrap(), secret_key);
+        assert!(from_secret_key_file(&secret_file[..95]).is_err());
+        assert_eq!(
+            to_public_key_file(&secret_key)[32..],
+            key.public().as_bytes()[..]
+        );
+        assert_eq!(to_hostname(&secret_key), format!("{}\n", onion));
+
+        let blob = to_control_blob(&secret_key);
+        assert!(blob.starts_with("ED25519-V3:"));
+        assert_eq!(from_control_blob(&blob).unwrap(), secret_key);
+        assert_eq!(
+            from_control_blob(&base64::encode(&secret_key[..])).unwrap(),
+            secret_key
+        );
+        assert!(from_control_blob(&base64::encode(&[0xffu8; 64][..])).is_err());
+        assert!(from_control_blob("ED25519-V3:c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn signs_with_onion_keys() {
+        let secret_key = generate_secret_key();
+        let address = onion_address(&secret_key).unwrap();
+        assert_eq!(to_hostname(&secret_key), format!("{}\n", address));
+        assert!(onion_address(&[0xffu8; 64]).is_err());
+
+        let signature = sign(&secret_key, b"pairing request").unwrap();
+        assert!(address.verify(b"pairing request", &signature));
+        assert!(!address.verify(b"pairing request!", &signature));
+        assert!(!address.verify(b"pairing request", &signature[..63]));
+        let other = onion_address(&generate_secret_key()).unwrap();
+        assert!(!other.verify(b"pairing request", &signature));
+    }
+
+    #[test]
+    fn round_trips_hidden_service_dirs() {
+        let dir = "/tmp/sifir_rs_sdk_hs_dir";
+        let _ = fs::remove_dir_all(dir);
+        let secret_key = TorSecretKeyV3::generate().as_bytes();
+        write_hidden_service_dir(&secret_key, dir).unwrap();
+        assert_eq!(read_hidden_service_dir(dir).unwrap(), secret_key);
+
+        let other = TorSecretKeyV3::generate().as_bytes();
+        fs::write(format!("{}/hostname", dir), to_hostname(&other)).unwrap();
+        assert!(read_hidden_service_dir(dir).is_err());
+        fs::remove_file(format!("{}/hostname", dir)).unwrap();
+        fs::remove_file(format!("{}/hs_ed25519_public_key", dir)).unwrap();
+        assert_eq!(read_hidden_service_dir(dir).unwrap(), secret_key);
+        assert!(read_hidden_service_dir("/tmp/sifir_rs_sdk_no_hs_dir").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::TorErrors;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+/// First byte of every blob we wrap so the format can change without breaking stored keys
+const WRAP_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Encrypts onion secret keys before they are stored in the registry or handed to the app.
+/// Hosts can implement it on top of the iOS Keychain or the Android Keystore so the wrapping key
+/// never leaves secure hardware, PassphraseWrapper and WrappingKey cover the other cases.
+pub trait KeyWrapper: Send {
+    fn wrap(&self, secret: &[u8]) -> Result<Vec<u8>, TorErrors>;
+    /// Must fail if the blob was wrapped with another key or was tampered with
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, TorErrors>;
+}
+
+/// Key derived from a passphrase with Argon2id, a fresh salt is used for every wrapped secret
+pub struct PassphraseWrapper {
+    passphrase: Zeroizing<String>,
+}
+
+impl PassphraseWrapper {
+    pub fn new(passphrase: &str) -> Result<Self, TorErrors> {
+        if passphrase.is_empty() {
+            return Err(TorErrors::KeystoreError(String::from("Empty passphrase")));
+        }
+        Ok(PassphraseWrapper {
+            passphrase: Zeroizing::new(String::from(passphrase)),
+        })
+    }
+
+    fn derive_key(&self, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, TorErrors> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key[..])
+            .map_err(|e| TorErrors::KeystoreError(format!("Argon2: {}", e)))?;
+        Ok(key)
+    }
+}
+
+impl KeyWrapper for PassphraseWrapper {
+    /// version || salt || nonce || ciphertext
+    fn wrap(&self, secret: &[u8]) -> Result<Vec<u8>, TorErrors> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = self.derive_key(&salt)?;
+        let mut wrapped = vec![WRAP_VERSION];
+        wrapped.extend_from_slice(&salt);
+        wrapped.extend(seal(&key, secret)?);
+        Ok(wrapped)
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, TorErrors> {
+        let sealed = check_version(wrapped)?;
+        if sealed.len() < SALT_LEN {
+            return Err(invalid_blob());
+        }
+        let (salt, sealed) = sealed.split_at(SALT_LEN);
+        open(&*self.derive_key(salt)?, sealed)
+    }
+}
+
+/// 32 byte key supplied by the host, typically generated and kept by the platform keystore
+pub struct WrappingKey {
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl WrappingKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        WrappingKey {
+            key: Zeroizing::new(key),
+        }
+    }
+
+    pub fn from_base64(key: &str) -> Result<Self, TorErrors> {
+        let mut decoded = Zeroizing::new([0u8; 32]);
+        match base64::decode_config_slice(key.trim(), base64::STANDARD, &mut decoded[..]) {
+            Ok(32) => Ok(WrappingKey { key: decoded }),
+            _ => Err(TorErrors::KeystoreError(String::from(
+                "Wrapping key must be 32 bytes base64 encoded",
+            ))),
+        }
+    }
+}
+
+impl KeyWrapper for WrappingKey {
+    /// version || nonce || ciphertext
+    fn wrap(&self, secret: &[u8]) -> Result<Vec<u8>, TorErrors> {
+        let mut wrapped = vec![WRAP_VERSION];
+        wrapped.extend(seal(&self.key, secret)?);
+        Ok(wrapped)
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, TorErrors> {
+        open(&self.key, check_version(wrapped)?)
+    }
+}
+
+/// Wraps an onion secret key, base64 encoded for storage
+pub(crate) fn seal_secret_key(
+    wrapper: &dyn KeyWrapper,
+    secret_key: &[u8; 64],
+) -> Result<String, TorErrors> {
+    Ok(base64::encode(wrapper.wrap(&secret_key[..])?))
+}
+
+pub(crate) fn open_secret_key(
+    wrapper: &dyn KeyWrapper,
+    sealed: &str,
+) -> Result<[u8; 64], TorErrors> {
+    let wrapped = base64::decode(sealed).map_err(|_| invalid_blob())?;
+    let secret = Zeroizing::new(wrapper.unwrap(&wrapped)?);
+    if secret.len() != 64 {
+        return Err(invalid_blob());
+    }
+    let mut secret_key = [0u8; 64];
+    secret_key.copy_from_slice(&secret);
+    Ok(secret_key)
+}
+
+/// nonce || ciphertext
+fn seal(key: &[u8; 32], secret: &[u8]) -> Result<Vec<u8>, TorErrors> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(XNonce::from_slice(&nonce), secret)
+        .map_err(|_| TorErrors::KeystoreError(String::from("Encryption failed")))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, TorErrors> {
+    if sealed.len() < NONCE_LEN {
+        return Err(invalid_blob());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            TorErrors::KeystoreError(String::from(
+                "Could not decrypt key, wrong passphrase or wrapping key",
+            ))
+        })
+}
+
+fn check_version(wrapped: &[u8]) -> Result<&[u8], TorErrors> {
+    match wrapped.split_first() {
+        Some((&WRAP_VERSION, rest)) => Ok(rest),
+        _ => Err(invalid_blob()),
+    }
+}
+
+fn invalid_blob() -> TorErrors {
+    TorErrors::KeystoreError(String::from("Invalid wrapped key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_and_unwraps_secret_keys() {
+        let secret_key = [7u8; 64];
+        let passphrase = PassphraseWrapper::new("correct horse").unwrap();
+        let sealed = seal_secret_key(&passphrase, &secret_key).unwrap();
+        assert!(!sealed.contains(&base64::encode(&secret_key[..])));
+        assert_ne!(sealed, seal_secret_key(&passphrase, &secret_key).unwrap());
+        assert_eq!(open_secret_key(&passphrase, &sealed).unwrap(), secret_key);
+        let wrong = PassphraseWrapper::new("battery staple").unwrap();
+        assert!(open_secret_key(&wrong, &sealed).is_err());
+        assert!(PassphraseWrapper::new("").is_err());
+
+        let host_key = WrappingKey::from_base64(&base64::encode([3u8; 32])).unwrap();
+        let sealed = seal_secret_key(&host_key, &secret_key).unwrap();
+        assert_eq!(open_secret_key(&host_key, &sealed).unwrap(), secret_key);
+        assert!(open_secret_key(&WrappingKey::new([4u8; 32]), &sealed).is_err());
+        assert!(open_secret_key(&passphrase, &sealed).is_err());
+        let mut tampered = base64::decode(&sealed).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(open_secret_key(&host_key, &base64::encode(&tampered)).is_err());
+        assert!(WrappingKey::from_base64("c2hvcnQ=").is_err());
+    }
+}
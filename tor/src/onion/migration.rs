@@ -0,0 +1,148 @@
+//! Announcement that an onion service moved to a new address, when its key is rotated.
+//! Peers trust it because it is signed with the key of the address they already know, the new
+//! key signs it too so an announcement can't point at an onion its author doesn't control.
+//! Both sign the same fixed length message:
+//! "sifir-rs-sdk onion migration v1" | old public key | new public key | retire_at (u64 BE)
+use crate::onion::{keys, OnionAddress};
+use crate::TorErrors;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MIGRATION_CONTEXT: &[u8] = b"sifir-rs-sdk onion migration v1";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnionMigration {
+    pub old_onion: OnionAddress,
+    pub new_onion: OnionAddress,
+    /// Unix time in seconds the old onion stops being served
+    pub retire_at: u64,
+    /// base64 signatures of the old and new keys
+    pub old_signature: String,
+    pub new_signature: String,
+}
+
+impl OnionMigration {
+    pub fn sign(
+        old_secret_key: &[u8; 64],
+        new_secret_key: &[u8; 64],
+        retire_at: u64,
+    ) -> Result<Self, TorErrors> {
+        let old_onion = keys::onion_address(old_secret_key)?;
+        let new_onion = keys::onion_address(new_secret_key)?;
+        if old_onion == new_onion {
+            return Err(TorErrors::OnionMigrationError(String::from(
+                "Old and new keys are the same",
+            )));
+        }
+        let message = signed_message(&old_onion, &new_onion, retire_at);
+        Ok(OnionMigration {
+            old_onion,
+            new_onion,
+            retire_at,
+            old_signature: base64::encode(&keys::sign(old_secret_key, &message)?[..]),
+            new_signature: base64::encode(&keys::sign(new_secret_key, &message)?[..]),
+        })
+    }
+
+    /// Checks both signatures, anything else than Ok must not be acted upon
+    pub fn verify(&self) -> Result<(), TorErrors> {
+        let message = signed_message(&self.old_onion, &self.new_onion, self.retire_at);
+        let signed = |onion: &OnionAddress, signature: &str| {
+            base64::decode(signature)
+                .map(|signature| onion.verify(&message, &signature))
+                .unwrap_or(false)
+        };
+        if !signed(&self.old_onion, &self.old_signature) {
+            return Err(TorErrors::OnionMigrationError(String::from(
+                "Invalid signature of the old onion",
+            )));
+        }
+        if !signed(&self.new_onion, &self.new_signature) {
+            return Err(TorErrors::OnionMigrationError(String::from(
+                "Invalid signature of the new onion",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Verifies the announcement and returns what a stored address becomes: the new onion, with
+    /// the port of stored if it had one, when stored is the old onion, None for other addresses
+    pub fn migrate(&self, stored: &str) -> Result<Option<OnionAddress>, TorErrors> {
+        self.verify()?;
+        let stored: OnionAddress = stored.parse()?;
+        if stored.public_key() != self.old_onion.public_key() {
+            return Ok(None);
+        }
+        Ok(Some(match stored.port() {
+            Some(port) => self.new_onion.with_port(port),
+            None => self.new_onion,
+        }))
+    }
+}
+
+fn signed_message(old_onion: &OnionAddress, new_onion: &OnionAddress, retire_at: u64) -> Vec<u8> {
+    let mut message = MIGRATION_CONTEXT.to_vec();
+    message.extend_from_slice(old_onion.public_key());
+    message.extend_from_slice(new_onion.public_key());
+    message.extend_from_slice(&retire_at.to_be_bytes());
+    message
+}
+
+/// Unix time in seconds, what retire_at is compared with
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_and_verifies_migrations() {
+        let old_key = keys::generate_secret_key();
+        let new_key = keys::generate_secret_key();
+        let migration = OnionMigration::sign(&old_key, &new_key, 1_700_000_000).unwrap();
+        assert!(migration.verify().is_ok());
+        let json = serde_json::to_string(&migration).unwrap();
+        let received: OnionMigration = serde_json::from_str(&json).unwrap();
+        assert_eq!(received, migration);
+
+        let old_onion = keys::onion_address(&old_key).unwrap();
+        let new_onion = keys::onion_address(&new_key).unwrap();
+        assert_eq!(
+            received
+                .migrate(&format!("{}", old_onion.with_port(9735)))
+                .unwrap(),
+            Some(new_onion.with_port(9735))
+        );
+        assert_eq!(
+            received.migrate(&old_onion.service_id()).unwrap(),
+            Some(new_onion)
+        );
+        let other = keys::onion_address(&keys::generate_secret_key()).unwrap();
+        assert_eq!(received.migrate(&other.to_string()).unwrap(), None);
+
+        // any change breaks a signature
+        let postponed = OnionMigration {
+            retire_at: migration.retire_at + 1,
+            ..migration.clone()
+        };
+        assert!(postponed.verify().is_err());
+        assert!(postponed.migrate(&old_onion.to_string()).is_err());
+        let hijacked = OnionMigration {
+            new_onion: other,
+            ..migration.clone()
+        };
+        assert!(hijacked.verify().is_err());
+        // signed by the new key only, the old one being the key peers trust
+        let unvouched = OnionMigration {
+            old_signature: migration.new_signature.clone(),
+            ..migration
+        };
+        assert!(unvouched.verify().is_err());
+        assert!(OnionMigration::sign(&old_key, &old_key, 0).is_err());
+    }
+}
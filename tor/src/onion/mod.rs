@@ -0,0 +1,303 @@
+pub mod address;
+pub mod client_auth;
+pub mod disk;
+pub mod health;
+pub(crate) mod hs_desc;
+pub mod keys;
+pub mod keystore;
+pub mod migration;
+pub mod probe;
+pub mod registry;
+pub mod seed;
+pub mod vanity;
+
+pub use address::OnionAddress;
+
+use crate::{HiddenServicePort, TorErrors};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use torut::onion::TorSecretKeyV3;
+
+/// Where connections to a virtual port of an onion service are forwarded to.
+/// Written the way Tor does: "127.0.0.1:8080", "[::1]:8080", "192.168.1.10:8080" or "unix:/path/to/socket"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum HiddenServiceTarget {
+    Tcp(SocketAddr),
+    /// Absolute path of a Unix domain socket, only processes allowed to open it can reach the backend
+    Unix(String),
+}
+
+impl HiddenServiceTarget {
+    /// Target port on 127.0.0.1
+    pub fn local(port: u16) -> Self {
+        HiddenServiceTarget::Tcp(SocketAddr::new(
+            IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
+            port,
+        ))
+    }
+}
+
+impl fmt::Display for HiddenServiceTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HiddenServiceTarget::Tcp(addr) => write!(f, "{}", addr),
+            HiddenServiceTarget::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+impl FromStr for HiddenServiceTarget {
+    type Err = TorErrors;
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target.strip_prefix("unix:") {
+            // ADD_ONION arguments are space separated so paths Tor would need quoted are refused
+            Some(path) => {
+                if path.starts_with('/')
+                    && !path
+                        .chars()
+                        .any(|c| c.is_whitespace() || c.is_control() || c == '"')
+                {
+                    Ok(HiddenServiceTarget::Unix(String::from(path)))
+                } else {
+                    Err(TorErrors::HiddenServiceParamError(format!(
+                        "Invalid unix socket path {}",
+                        path
+                    )))
+                }
+            }
+            None => target
+                .parse::<SocketAddr>()
+                .map(HiddenServiceTarget::Tcp)
+                .map_err(|_| {
+                    TorErrors::HiddenServiceParamError(format!("Invalid target {}", target))
+                }),
+        }
+    }
+}
+
+impl TryFrom<String> for HiddenServiceTarget {
+    type Error = TorErrors;
+    fn try_from(target: String) -> Result<Self, Self::Error> {
+        target.parse()
+    }
+}
+
+impl From<HiddenServiceTarget> for String {
+    fn from(target: HiddenServiceTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// An onion service Tor is publishing, as returned by `OwnedTorService::list_onions`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnionServiceDescriptor {
+    pub onion: String,
+    /// Detached services outlive the control connection that created them
+    pub detached: bool,
+    /// Created by this OwnedTorService, ports are only known for those
+    pub hosted: bool,
+    pub ports: Vec<HiddenServicePort>,
+    /// Recorded in the registry, see `OwnedTorService::add_registered_hidden_service`
+    pub registered: bool,
+}
+
+/// ADD_ONION options kept with a service so it is always republished the same way
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default)]
+pub(crate) struct OnionServiceOptions {
+    /// Not removed when the control connection that created it closes
+    pub(crate) detached: bool,
+    /// Streams allowed per rendezvous circuit
+    pub(crate) max_streams: Option<u16>,
+    /// Tear the circuit down when max_streams is exceeded instead of just refusing the stream
+    pub(crate) max_streams_close_circuit: bool,
+    /// Single onion service, Tor wants it on every service of a non anonymous server and on none
+    /// otherwise. Follows the node so it isn't saved in the registry
+    #[serde(skip)]
+    pub(crate) non_anonymous: bool,
+}
+
+/// Builds the ADD_ONION command for a v3 service with the given key and port mappings
+pub(crate) fn add_onion_command(
+    key: &TorSecretKeyV3,
+    ports: &[HiddenServicePort],
+    options: &OnionServiceOptions,
+) -> Result<String, TorErrors> {
+    if ports.is_empty() {
+        return Err(TorErrors::HiddenServiceParamError(String::from(
+            "No port mapping given",
+        )));
+    }
+    let mut command = format!(
+        "ADD_ONION ED25519-V3:{}",
+        base64::encode(&key.as_bytes()[..])
+    );
+    let mut flags = Vec::new();
+    if options.detached {
+        flags.push("Detach");
+    }
+    if options.max_streams_close_circuit {
+        if options.max_streams.is_none() {
+            return Err(TorErrors::HiddenServiceParamError(String::from(
+                "max_streams_close_circuit needs max_streams",
+            )));
+        }
+        flags.push("MaxStreamsCloseCircuit");
+    }
+    if options.non_anonymous {
+        flags.push("NonAnonymous");
+    }
+    if !flags.is_empty() {
+        command.push_str(&format!(" Flags={}", flags.join(",")));
+    }
+    if let Some(max_streams) = options.max_streams {
+        command.push_str(&format!(" MaxStreams={}", max_streams));
+    }
+    for port in ports {
+        // Re-validate as targets can be built without going through FromStr
+        let target: HiddenServiceTarget = port.target.to_string().parse()?;
+        command.push_str(&format!(" Port={},{}", port.hs_port, target));
+    }
+    Ok(command)
+}
+
+/// Service ids of a GETINFO onions/current or onions/detached reply, the ids come either as
+/// the value of a single line or as a data block with one id per line
+pub(crate) fn parse_onions_getinfo(key: &str, lines: &[String]) -> Vec<String> {
+    let prefix = format!("{}=", key);
+    lines
+        .iter()
+        .filter_map(|line| line.strip_prefix(&prefix))
+        .flat_map(|value| value.split_whitespace())
+        .filter(|id| service_id(id).is_ok())
+        .map(String::from)
+        .collect()
+}
+
+/// Service id of an onion address given with or without .onion and port, checking it is a valid
+/// v3 address
+pub(crate) fn service_id(onion: &str) -> Result<String, TorErrors> {
+    Ok(onion.parse::<OnionAddress>()?.service_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION_A: &str = "kciybn4d4vuqvobdl2kdp3r2rudqbqvsymqwg4jomzft6m6gaibaf6yd";
+    const ONION_B: &str = "udfpzbte2hommnvag5f3qlouqkhvp3xybhlus2yvfeqdwlhjroe4bbyd";
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            "[::1]:8080".parse::<HiddenServiceTarget>().unwrap(),
+            HiddenServiceTarget::Tcp("[::1]:8080".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/data/sifir/backend.sock"
+                .parse::<HiddenServiceTarget>()
+                .unwrap(),
+            HiddenServiceTarget::Unix(String::from("/data/sifir/backend.sock"))
+        );
+        assert!("unix:relative.sock".parse::<HiddenServiceTarget>().is_err());
+        assert!("unix:/tmp/a b.sock".parse::<HiddenServiceTarget>().is_err());
+        assert!("localhost:80".parse::<HiddenServiceTarget>().is_err());
+        assert_eq!(HiddenServiceTarget::local(80).to_string(), "127.0.0.1:80");
+
+        let port: HiddenServicePort =
+            serde_json::from_str(r#"{"hs_port":80,"target":"unix:/tmp/backend.sock"}"#).unwrap();
+        assert_eq!(
+            port.target,
+            HiddenServiceTarget::Unix(String::from("/tmp/backend.sock"))
+        );
+        assert!(
+            serde_json::from_str::<HiddenServicePort>(r#"{"hs_port":80,"target":"nope"}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn builds_add_onion_command() {
+        let key = TorSecretKeyV3::generate();
+        let command = add_onion_command(
+            &key,
+            &[
+                HiddenServicePort {
+                    hs_port: 80,
+                    target: HiddenServiceTarget::local(8080),
+                },
+                HiddenServicePort {
+                    hs_port: 9735,
+                    target: HiddenServiceTarget::Unix(String::from("/tmp/ln.sock")),
+                },
+            ],
+            &OnionServiceOptions::default(),
+        )
+        .unwrap();
+        assert!(command.starts_with("ADD_ONION ED25519-V3:"));
+        assert!(command.ends_with(" Port=80,127.0.0.1:8080 Port=9735,unix:/tmp/ln.sock"));
+        assert!(add_onion_command(&key, &[], &OnionServiceOptions::default()).is_err());
+        let injected = HiddenServicePort {
+            hs_port: 80,
+            target: HiddenServiceTarget::Unix(String::from("/tmp/x\r\nSIGNAL SHUTDOWN")),
+        };
+        assert!(add_onion_command(&key, &[injected], &OnionServiceOptions::default()).is_err());
+        let ports = [HiddenServicePort::local(80, 8080)];
+        let mut options = OnionServiceOptions {
+            detached: true,
+            ..Default::default()
+        };
+        let detached = add_onion_command(&key, &ports, &options).unwrap();
+        assert!(detached.contains(" Flags=Detach Port=80,127.0.0.1:8080"));
+        options.max_streams_close_circuit = true;
+        assert!(add_onion_command(&key, &ports, &options).is_err());
+        options.max_streams = Some(8);
+        let limited = add_onion_command(&key, &ports, &options).unwrap();
+        assert!(limited
+            .contains(" Flags=Detach,MaxStreamsCloseCircuit MaxStreams=8 Port=80,127.0.0.1:8080"));
+        let single_onion = OnionServiceOptions {
+            non_anonymous: true,
+            ..Default::default()
+        };
+        assert!(add_onion_command(&key, &ports, &single_onion)
+            .unwrap()
+            .contains(" Flags=NonAnonymous Port=80,127.0.0.1:8080"));
+        // the mode comes from the node, not from saved options
+        let saved = serde_json::to_string(&single_onion).unwrap();
+        assert!(
+            !serde_json::from_str::<OnionServiceOptions>(&saved)
+                .unwrap()
+                .non_anonymous
+        );
+        assert_eq!(
+            service_id(&format!("{}.onion:80", ONION_A)).unwrap(),
+            ONION_A
+        );
+        assert!(service_id("abc234.onion").is_err());
+        assert!(service_id("abc Flags=Detach").is_err());
+    }
+
+    #[test]
+    fn parses_onions_getinfo() {
+        let single = vec![
+            String::from("onions/current=pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd"),
+            String::from("OK"),
+        ];
+        assert_eq!(
+            parse_onions_getinfo("onions/current", &single),
+            vec!["pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd"]
+        );
+        let block = vec![
+            format!("onions/detached=\r\n{}\r\n{}\r\nabc234", ONION_A, ONION_B),
+            String::from("OK"),
+        ];
+        assert_eq!(
+            parse_onions_getinfo("onions/detached", &block),
+            vec![ONION_A, ONION_B]
+        );
+        assert!(parse_onions_getinfo("onions/current", &block).is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! Reachability of a remote onion service: is its descriptor published and does a port accept
+//! connections. The SOCKS handshake is done here rather than with the socks crate because the
+//! reply code is what tells the failures apart, Tor sends the extended ones (0xF0..0xF7) since
+//! our SocksPort has ExtendedErrors.
+use crate::TorErrors;
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ProbeOutcome {
+    /// No HSDir has a descriptor for it, the service is offline or never was published
+    DescriptorNotFound,
+    /// Descriptor found, no port was probed
+    DescriptorFound,
+    /// None of the introduction points answered, the service is most likely offline
+    IntroFailed,
+    /// The service did not join the rendezvous circuit
+    RendezvousFailed,
+    /// The service requires client authorization we don't have or have wrong
+    ClientAuthFailed,
+    /// The service is up but refused the port
+    PortClosed,
+    Reachable,
+    /// Ran out of time before Tor gave an answer
+    TimedOut,
+    /// Any other SOCKS failure, see reason
+    Unreachable,
+}
+
+/// Result of `OwnedTorService::probe_onion`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnionProbe {
+    pub onion: String,
+    pub outcome: ProbeOutcome,
+    /// Time to fetch the descriptor
+    pub descriptor_ms: Option<u64>,
+    /// Time to get a SOCKS reply for the port, when it was probed
+    pub connect_ms: Option<u64>,
+    /// HSFETCH failure reason or SOCKS reply code
+    pub reason: Option<String>,
+}
+
+/// Outcome of a SOCKS5 reply code (RFC 1928 and Tor's ExtendedErrors, socks-extensions.txt)
+pub(crate) fn socks_reply_outcome(code: u8) -> ProbeOutcome {
+    match code {
+        0x00 => ProbeOutcome::Reachable,
+        0x05 => ProbeOutcome::PortClosed,
+        0x06 => ProbeOutcome::TimedOut,
+        0xF0 | 0xF1 | 0xF6 => ProbeOutcome::DescriptorNotFound,
+        0xF2 | 0xF7 => ProbeOutcome::IntroFailed,
+        0xF3 => ProbeOutcome::RendezvousFailed,
+        0xF4 | 0xF5 => ProbeOutcome::ClientAuthFailed,
+        _ => ProbeOutcome::Unreachable,
+    }
+}
+
+/// Asks the SOCKS proxy to connect to host:port and returns the reply code, None if no reply came
+/// before timeout. The connection is closed right after, nothing is sent to the service.
+pub(crate) fn socks_connect(
+    proxy: SocketAddr,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<Option<u8>, TorErrors> {
+    if host.len() > 255 {
+        return Err(TorErrors::TcpStreamError(format!("Host too long {}", host)));
+    }
+    let deadline = Instant::now() + timeout;
+    let mut stream = TcpStream::connect_timeout(&proxy, timeout)?;
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    // greeting without authentication, then CONNECT with a domain name
+    let mut greeting_reply = [0u8; 2];
+    let mut reply = [0u8; 4];
+    let exchanged = (|| {
+        set_timeouts(&stream, deadline)?;
+        stream.write_all(&[0x05, 0x01, 0x00])?;
+        stream.read_exact(&mut greeting_reply)?;
+        if greeting_reply != [0x05, 0x00] {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "SOCKS proxy refused no authentication",
+            ));
+        }
+        set_timeouts(&stream, deadline)?;
+        stream.write_all(&request)?;
+        stream.read_exact(&mut reply)
+    })();
+    match exchanged {
+        Ok(_) if reply[0] == 0x05 => Ok(Some(reply[1])),
+        Ok(_) => Err(TorErrors::TcpStreamError(String::from(
+            "Invalid SOCKS reply",
+        ))),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(TorErrors::IoError(e)),
+    }
+}
+
+fn set_timeouts(stream: &TcpStream, deadline: Instant) -> std::io::Result<()> {
+    let left = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| *left > Duration::from_millis(0))
+        .ok_or_else(|| std::io::Error::new(ErrorKind::TimedOut, "SOCKS timeout"))?;
+    stream.set_read_timeout(Some(left))?;
+    stream.set_write_timeout(Some(left))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// SOCKS proxy answering every CONNECT with code, returns its address
+    pub(crate) fn fake_socks_proxy(code: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let mut greeting = [0u8; 3];
+                // VER CMD RSV ATYP=3 LEN
+                let mut header = [0u8; 5];
+                if stream.read_exact(&mut greeting).is_err()
+                    || stream.write_all(&[0x05, 0x00]).is_err()
+                    || stream.read_exact(&mut header).is_err()
+                {
+                    continue;
+                }
+                let mut rest = vec![0u8; header[4] as usize + 2];
+                if stream.read_exact(&mut rest).is_ok() {
+                    let _ = stream.write_all(&[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn maps_socks_replies() {
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        for (code, outcome) in [
+            (0x00, ProbeOutcome::Reachable),
+            (0x05, ProbeOutcome::PortClosed),
+            (0xF0, ProbeOutcome::DescriptorNotFound),
+            (0xF2, ProbeOutcome::IntroFailed),
+            (0xF3, ProbeOutcome::RendezvousFailed),
+            (0xF5, ProbeOutcome::ClientAuthFailed),
+            (0x01, ProbeOutcome::Unreachable),
+        ]
+        .iter()
+        {
+            let proxy = fake_socks_proxy(*code);
+            let reply = socks_connect(proxy, onion, 80, Duration::from_secs(2)).unwrap();
+            assert_eq!(reply, Some(*code));
+            assert_eq!(socks_reply_outcome(*code), *outcome);
+        }
+
+        // a proxy that never answers
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reply = socks_connect(
+            silent.local_addr().unwrap(),
+            onion,
+            80,
+            Duration::from_millis(200),
+        )
+        .unwrap();
+        assert_eq!(reply, None);
+    }
+}
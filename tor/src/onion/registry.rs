@@ -0,0 +1,229 @@
+use super::keystore::{open_secret_key, seal_secret_key, KeyWrapper};
+use super::{service_id, OnionServiceOptions};
+use crate::{HiddenServicePort, TorErrors};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const REGISTRY_FILE: &str = "onion_services.json";
+
+/// Everything needed to publish a service again: key, port mappings and options
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RegistryEntry {
+    /// Expanded ed25519 secret key sealed with the keystore
+    pub(crate) secret_key: String,
+    pub(crate) ports: Vec<HiddenServicePort>,
+    #[serde(flatten)]
+    pub(crate) options: OnionServiceOptions,
+    /// Unix time in seconds a rotated service stops being published, see
+    /// `OwnedTorService::rotate_hidden_service`
+    #[serde(default)]
+    pub(crate) retire_at: Option<u64>,
+}
+
+impl RegistryEntry {
+    /// Keys are always sealed with the keystore, none is written in the clear
+    pub(crate) fn new(
+        secret_key: &[u8; 64],
+        ports: Vec<HiddenServicePort>,
+        keystore: &dyn KeyWrapper,
+    ) -> Result<Self, TorErrors> {
+        Ok(RegistryEntry {
+            secret_key: seal_secret_key(keystore, secret_key)?,
+            ports,
+            options: OnionServiceOptions::default(),
+            retire_at: None,
+        })
+    }
+
+    pub(crate) fn secret_key_bytes(
+        &self,
+        keystore: &dyn KeyWrapper,
+    ) -> Result<[u8; 64], TorErrors> {
+        open_secret_key(keystore, &self.secret_key)
+    }
+}
+
+/// A service of the registry as exposed to users, without its key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegisteredHiddenService {
+    pub onion: String,
+    pub ports: Vec<HiddenServicePort>,
+    pub detached: bool,
+}
+
+/// Onion services the SDK republishes every time the daemon bootstraps, stored as JSON in the
+/// data dir keyed by service id. Ephemeral services created with `create_hidden_service` are not in it.
+pub(crate) struct OnionRegistry {
+    path: String,
+    services: BTreeMap<String, RegistryEntry>,
+}
+
+impl OnionRegistry {
+    pub(crate) fn load(base_dir: &str) -> Result<Self, TorErrors> {
+        let path = format!("{}/{}", base_dir, REGISTRY_FILE);
+        let services = if Path::new(&path).exists() {
+            let contents = fs::read_to_string(&path)?;
+            serde_json::from_str(&contents).map_err(|e| {
+                TorErrors::OnionRegistryError(format!("Error reading {}: {}", path, e))
+            })?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(OnionRegistry { path, services })
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&String, &RegistryEntry)> {
+        self.services.iter()
+    }
+
+    pub(crate) fn list(&self) -> Vec<RegisteredHiddenService> {
+        self.services
+            .iter()
+            .map(|(id, entry)| RegisteredHiddenService {
+                onion: format!("{}.onion", id),
+                ports: entry.ports.clone(),
+                detached: entry.options.detached,
+            })
+            .collect()
+    }
+
+    pub(crate) fn contains(&self, service_id: &str) -> bool {
+        self.services.contains_key(service_id)
+    }
+
+    pub(crate) fn insert(&mut self, onion: &str, entry: RegistryEntry) -> Result<(), TorErrors> {
+        self.services.insert(service_id(onion)?, entry);
+        self.save()
+    }
+
+    /// Returns false if the service was not registered
+    pub(crate) fn remove(&mut self, onion: &str) -> Result<bool, TorErrors> {
+        if self.services.remove(&service_id(onion)?).is_none() {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    pub(crate) fn set_retire_at(&mut self, onion: &str, retire_at: u64) -> Result<(), TorErrors> {
+        if let Some(entry) = self.services.get_mut(&service_id(onion)?) {
+            entry.retire_at = Some(retire_at);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Fails if `keystore` can't open every key of the registry
+    pub(crate) fn check_keystore(&self, keystore: &dyn KeyWrapper) -> Result<(), TorErrors> {
+        for entry in self.services.values() {
+            entry.secret_key_bytes(keystore)?;
+        }
+        Ok(())
+    }
+
+    /// Wraps every key opened with `from` with `to`. Nothing is written unless all of them could
+    /// be opened so a wrong passphrase leaves the registry untouched.
+    pub(crate) fn reseal(
+        &mut self,
+        from: &dyn KeyWrapper,
+        to: &dyn KeyWrapper,
+    ) -> Result<(), TorErrors> {
+        let mut services = self.services.clone();
+        for entry in services.values_mut() {
+            let secret_key = entry.secret_key_bytes(from)?;
+            entry.secret_key = seal_secret_key(to, &secret_key)?;
+        }
+        self.services = services;
+        self.save()
+    }
+
+    /// Writes to a temp file renamed over the registry so a crash never leaves it half written
+    fn save(&self) -> Result<(), TorErrors> {
+        let tmp_path = format!("{}.tmp", self.path);
+        let contents = serde_json::to_string_pretty(&self.services)
+            .map_err(|e| TorErrors::OnionRegistryError(format!("{}", e)))?;
+        fs::write(&tmp_path, contents)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::keystore::WrappingKey;
+    use super::*;
+
+    #[test]
+    fn persists_registered_services() {
+        let base_dir = "/tmp/sifir_rs_sdk_registry";
+        let _ = fs::remove_dir_all(base_dir);
+        fs::create_dir_all(base_dir).unwrap();
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        let wrapper = WrappingKey::new([9; 32]);
+        let mut registry = OnionRegistry::load(base_dir).unwrap();
+        assert!(registry.list().is_empty());
+        registry
+            .insert(
+                onion,
+                RegistryEntry::new(&[1; 64], vec![HiddenServicePort::local(80, 8080)], &wrapper)
+                    .unwrap(),
+            )
+            .unwrap();
+        registry.set_retire_at(onion, 1_700_000_000).unwrap();
+
+        let mut registry = OnionRegistry::load(base_dir).unwrap();
+        let services = registry.list();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].onion, onion);
+        let (_, entry) = registry.entries().next().unwrap();
+        assert_eq!(entry.secret_key_bytes(&wrapper).unwrap(), [1; 64]);
+        assert_eq!(entry.retire_at, Some(1_700_000_000));
+        assert!(registry.remove(onion).unwrap());
+        assert!(!registry.remove(onion).unwrap());
+        assert!(OnionRegistry::load(base_dir).unwrap().list().is_empty());
+
+        let entry: RegistryEntry =
+            serde_json::from_str(r#"{"secret_key":"","ports":[],"detached":true,"max_streams":4}"#)
+                .unwrap();
+        assert!(entry.options.detached && !entry.options.max_streams_close_circuit);
+        assert_eq!(entry.options.max_streams, Some(4));
+        assert_eq!(entry.retire_at, None);
+    }
+
+    #[test]
+    fn seals_registered_keys() {
+        let base_dir = "/tmp/sifir_rs_sdk_registry_sealed";
+        let _ = fs::remove_dir_all(base_dir);
+        fs::create_dir_all(base_dir).unwrap();
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        let ports = vec![HiddenServicePort::local(80, 8080)];
+        let wrapper = WrappingKey::new([9; 32]);
+        let mut registry = OnionRegistry::load(base_dir).unwrap();
+        registry
+            .insert(
+                onion,
+                RegistryEntry::new(&[1; 64], ports, &wrapper).unwrap(),
+            )
+            .unwrap();
+        let stored = fs::read_to_string(format!("{}/{}", base_dir, REGISTRY_FILE)).unwrap();
+        assert!(!stored.contains(&base64::encode(&[1u8; 64][..])));
+
+        let mut registry = OnionRegistry::load(base_dir).unwrap();
+        let (_, entry) = registry.entries().next().unwrap();
+        assert_eq!(entry.secret_key_bytes(&wrapper).unwrap(), [1; 64]);
+        let other = WrappingKey::new([8; 32]);
+        assert!(registry.check_keystore(&wrapper).is_ok());
+        assert!(registry.check_keystore(&other).is_err());
+        assert!(registry.reseal(&other, &other).is_err());
+        registry.reseal(&wrapper, &other).unwrap();
+        let (_, entry) = registry.entries().next().unwrap();
+        assert_eq!(entry.secret_key_bytes(&other).unwrap(), [1; 64]);
+        assert!(entry.secret_key_bytes(&wrapper).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Onion keys derived from a wallet seed, so restoring the wallet brings back the same onion
+//! addresses without backing up their keys.
+//! Each label ("payments", "pairing/0"...) gets an independent key: HKDF-SHA512 over the seed with
+//! the label as info gives an ed25519 seed, expanded the way Tor and ed25519-dalek do.
+//! Changing any of this changes every derived address, hence the version in the salt.
+use crate::TorErrors;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha512};
+use zeroize::Zeroizing;
+
+const DERIVATION_SALT: &[u8] = b"sifir-rs-sdk onion key v1";
+/// Below 128 bits the seed could be brute forced from the onion address
+const MIN_SEED_LEN: usize = 16;
+
+/// Derives the 64 byte expanded secret key of label from seed, usable as
+/// TorHiddenServiceParam.secret_key
+pub fn derive_secret_key(seed: &[u8], label: &str) -> Result<[u8; 64], TorErrors> {
+    if seed.len() < MIN_SEED_LEN {
+        return Err(TorErrors::OnionKeyError(format!(
+            "Seed must be at least {} bytes",
+            MIN_SEED_LEN
+        )));
+    }
+    if label.is_empty() {
+        return Err(TorErrors::OnionKeyError(String::from(
+            "Empty derivation label",
+        )));
+    }
+    let mut ed25519_seed = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha512>::new(Some(DERIVATION_SALT), seed)
+        .expand(label.as_bytes(), &mut ed25519_seed[..])
+        .map_err(|_| TorErrors::OnionKeyError(String::from("HKDF expansion failed")))?;
+    let mut secret_key = [0u8; 64];
+    secret_key.copy_from_slice(&Sha512::digest(&ed25519_seed[..]));
+    secret_key[0] &= 248;
+    secret_key[31] &= 63;
+    secret_key[31] |= 64;
+    Ok(secret_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::keys;
+    use torut::onion::TorSecretKeyV3;
+
+    fn onion(secret_key: [u8; 64]) -> String {
+        TorSecretKeyV3::from(secret_key)
+            .public()
+            .get_onion_address()
+            .to_string()
+    }
+
+    #[test]
+    fn derives_stable_keys_from_seeds() {
+        let seed = [42u8; 32];
+        let payments = derive_secret_key(&seed, "payments").unwrap();
+        assert_eq!(payments, derive_secret_key(&seed, "payments").unwrap());
+        assert_ne!(payments, derive_secret_key(&seed, "pairing").unwrap());
+        assert_ne!(
+            payments,
+            derive_secret_key(&[43u8; 32], "payments").unwrap()
+        );
+        // valid expanded key Tor accepts
+        assert_eq!(
+            keys::from_control_blob(&keys::to_control_blob(&payments)).unwrap(),
+            payments
+        );
+        // pinned so a change of derivation can't silently move restored wallets to new addresses
+        assert_eq!(
+            onion(payments),
+            "jjfycu25opq2vb6eieseyuxkmsdk2e2o22hvp7o4sko2754mkibqsiad.onion"
+        );
+
+        assert!(derive_secret_key(&[1u8; 15], "payments").is_err());
+        assert!(derive_secret_key(&seed, "").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Brute-forces v3 onion keys whose address starts with a chosen prefix, every extra character
+//! makes the search 32 times longer: 5 characters take seconds, 7 take hours on a phone.
+use crate::TorErrors;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use torut::onion::TorSecretKeyV3;
+
+/// Only the first 51 characters of an address are derived from the public key alone
+const MAX_PREFIX_LEN: usize = 51;
+/// Keys tried by a worker between two checks of the shared flags
+const BATCH_SIZE: u64 = 64;
+
+/// Key found by a vanity search, secret_key can be used as TorHiddenServiceParam.secret_key
+pub struct VanityKey {
+    pub onion: String,
+    pub secret_key: [u8; 64],
+}
+
+/// Snapshot of a running search
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VanityProgress {
+    pub attempts: u64,
+    pub elapsed_ms: u64,
+    pub keys_per_sec: u64,
+    /// Average number of keys to try for the prefix (32^len)
+    pub expected_attempts: f64,
+    /// Average time still needed at the current rate. The search has no memory so this does not
+    /// go down as keys are tried, it only gets more precise as the rate is measured.
+    pub eta_ms: Option<u64>,
+    pub found: bool,
+    pub cancelled: bool,
+}
+
+/// A search running on its own threads, dropping it cancels it
+pub struct VanitySearch {
+    prefix: String,
+    started: Instant,
+    attempts: Arc<AtomicU64>,
+    done: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+    result: Arc<Mutex<Option<VanityKey>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl VanitySearch {
+    /// Starts searching for an address starting with prefix (base32: a-z and 2-7) on threads
+    /// workers, all cores if None
+    pub fn start(prefix: &str, threads: Option<usize>) -> Result<Self, TorErrors> {
+        let prefix = prefix.trim().to_lowercase();
+        if prefix.is_empty()
+            || prefix.len() > MAX_PREFIX_LEN
+            || !prefix.chars().all(|c| matches!(c, 'a'..='z' | '2'..='7'))
+        {
+            return Err(TorErrors::HiddenServiceParamError(format!(
+                "Invalid vanity prefix {}, expected at most {} characters of a-z and 2-7",
+                prefix, MAX_PREFIX_LEN
+            )));
+        }
+        let threads = threads.unwrap_or_else(num_cpus::get).max(1);
+        let mut search = VanitySearch {
+            prefix,
+            started: Instant::now(),
+            attempts: Arc::new(AtomicU64::new(0)),
+            done: Arc::new(AtomicBool::new(false)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            result: Arc::new(Mutex::new(None)),
+            workers: Vec::with_capacity(threads),
+        };
+        for _ in 0..threads {
+            let prefix = search.prefix.clone();
+            let attempts = search.attempts.clone();
+            let done = search.done.clone();
+            let result = search.result.clone();
+            search.workers.push(std::thread::spawn(move || {
+                search_worker(&prefix, &attempts, &done, &result)
+            }));
+        }
+        Ok(search)
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Stops the workers, wait then returns an error unless a key was found before
+    pub fn cancel(&self) {
+        if !self.done.swap(true, Ordering::SeqCst) {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub fn progress(&self) -> VanityProgress {
+        let attempts = self.attempts.load(Ordering::Relaxed);
+        let elapsed_ms = self.started.elapsed().as_millis() as u64;
+        let keys_per_sec = if elapsed_ms > 0 {
+            attempts * 1000 / elapsed_ms
+        } else {
+            0
+        };
+        let expected_attempts = 32f64.powi(self.prefix.len() as i32);
+        let eta_ms = if keys_per_sec > 0 {
+            Some((expected_attempts / keys_per_sec as f64 * 1000.0).min(u64::MAX as f64) as u64)
+        } else {
+            None
+        };
+        let cancelled = self.cancelled.load(Ordering::SeqCst);
+        VanityProgress {
+            attempts,
+            elapsed_ms,
+            keys_per_sec,
+            expected_attempts,
+            eta_ms,
+            found: self.done.load(Ordering::SeqCst) && !cancelled,
+            cancelled,
+        }
+    }
+
+    /// Blocks until a key is found or the search is cancelled, the key is only returned once
+    pub fn wait(&mut self) -> Result<VanityKey, TorErrors> {
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        self.result.lock().unwrap().take().ok_or_else(|| {
+            TorErrors::HiddenServiceParamError(format!(
+                "No key for {}, the search was cancelled or its key already taken",
+                self.prefix
+            ))
+        })
+    }
+}
+
+impl Drop for VanitySearch {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+fn search_worker(
+    prefix: &str,
+    attempts: &AtomicU64,
+    done: &AtomicBool,
+    result: &Mutex<Option<VanityKey>>,
+) {
+    while !done.load(Ordering::Relaxed) {
+        for tried in 1..=BATCH_SIZE {
+            let key = TorSecretKeyV3::generate();
+            let onion = key.public().get_onion_address().to_string();
+            if onion.starts_with(prefix) {
+                // the partial batch counts too, a match in the first one would report 0 attempts
+                attempts.fetch_add(tried, Ordering::Relaxed);
+                let mut result = result.lock().unwrap();
+                // Another worker may have found one at the same time or the search been cancelled
+                if !done.swap(true, Ordering::SeqCst) {
+                    *result = Some(VanityKey {
+                        onion,
+                        secret_key: key.as_bytes(),
+                    });
+                }
+                return;
+            }
+        }
+        attempts.fetch_add(BATCH_SIZE, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_and_cancels_vanity_keys() {
+        assert!(VanitySearch::start("", None).is_err());
+        assert!(VanitySearch::start("abc1", None).is_err());
+        assert!(VanitySearch::start(&"a".repeat(52), None).is_err());
+
+        let mut search = VanitySearch::start("S", Some(2)).unwrap();
+        assert_eq!(search.prefix(), "s");
+        let found = search.wait().unwrap();
+        let progress = search.progress();
+        assert!(progress.found && progress.attempts > 0);
+        assert!(search.wait().is_err());
+        assert!(found.onion.starts_with('s'));
+        let key: TorSecretKeyV3 = found.secret_key.into();
+        assert_eq!(key.public().get_onion_address().to_string(), found.onion);
+
+        let mut search = VanitySearch::start("zzzzzzzzzz", Some(1)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let progress = search.progress();
+        assert!(progress.attempts > 0 && !progress.found);
+        assert!(progress.eta_ms.unwrap() > 0);
+        search.cancel();
+        assert!(search.progress().cancelled);
+        assert!(search.wait().is_err());
+    }
+}
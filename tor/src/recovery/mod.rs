@@ -0,0 +1,219 @@
+use crate::TorErrors;
+use fs2::FileExt;
+use logger::log::*;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// Consensus documents Tor caches, a complete one always ends with its signature
+const CACHED_CONSENSUS_FILES: [&str; 2] = ["cached-consensus", "cached-microdesc-consensus"];
+const CONSENSUS_FOOTER: &str = "-----END SIGNATURE-----";
+
+/// Repair done on the data directory before starting the daemon
+#[derive(Debug, PartialEq)]
+pub enum RecoveryAction {
+    /// Control port file pointing to a control port that is no longer listening
+    RemovedStaleControlFile(String),
+    /// Cached consensus that was only partially written
+    RemovedPartialConsensus(String),
+    /// Temporary file of an interrupted write
+    RemovedTempFile(String),
+}
+
+/// Startup recovery phase run before the Tor daemon is started in `base_dir`.
+/// Detects leftovers of a killed process (ctl.info, half written cache) and removes them.
+/// Errors out with DataDirectoryInUse if a live daemon is still using the directory.
+pub(crate) fn recover_data_dir(base_dir: &str) -> Result<Vec<RecoveryAction>, TorErrors> {
+    let mut actions = Vec::new();
+    let ctl_file_path = format!("{}/ctl.info", base_dir);
+    let lock_path = format!("{}/data/lock", base_dir);
+
+    if is_locked(&lock_path)? {
+        return Err(TorErrors::DataDirectoryInUse(format!(
+            "{} is held by a running Tor daemon",
+            lock_path
+        )));
+    }
+    if let Ok(ctl_info) = fs::read_to_string(&ctl_file_path) {
+        if let Some(control_addr) = parse_control_port_file(&ctl_info) {
+            if is_control_port_alive(&control_addr) {
+                return Err(TorErrors::DataDirectoryInUse(format!(
+                    "Tor daemon still running on control port {} for {}",
+                    control_addr, base_dir
+                )));
+            }
+        }
+        remove_file(&ctl_file_path)?;
+        actions.push(RecoveryAction::RemovedStaleControlFile(ctl_file_path));
+    }
+    for dir in &[format!("{}/data", base_dir), format!("{}/cache", base_dir)] {
+        for consensus in CACHED_CONSENSUS_FILES.iter() {
+            let path = format!("{}/{}", dir, consensus);
+            if let Ok(contents) = fs::read(&path) {
+                let complete = String::from_utf8_lossy(&contents)
+                    .trim_end()
+                    .ends_with(CONSENSUS_FOOTER);
+                if !complete {
+                    remove_file(&path)?;
+                    actions.push(RecoveryAction::RemovedPartialConsensus(path));
+                }
+            }
+        }
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().map(|e| e == "tmp").unwrap_or(false) {
+                    let path = path.to_string_lossy().to_string();
+                    remove_file(&path)?;
+                    actions.push(RecoveryAction::RemovedTempFile(path));
+                }
+            }
+        }
+    }
+
+    for action in actions.iter() {
+        warn!("Recovered Tor data directory: {:?}", action);
+    }
+    Ok(actions)
+}
+
+fn remove_file(path: &str) -> Result<(), TorErrors> {
+    fs::remove_file(path).map_err(|e| {
+        TorErrors::DataDirRecoveryError(format!("Unable to remove {}: {}", path, e))
+    })
+}
+
+/// Tor keeps an flock on data/lock while it runs, the file a killed daemon leaves behind is
+/// harmless and is locked again on start so it is never removed, only probed
+fn is_locked(lock_path: &str) -> Result<bool, TorErrors> {
+    let lock = match fs::File::open(lock_path) {
+        Ok(lock) => lock,
+        Err(_) => return Ok(false),
+    };
+    match lock.try_lock_exclusive() {
+        Ok(_) => {
+            let _ = lock.unlock();
+            Ok(false)
+        }
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(true),
+        Err(e) => Err(TorErrors::DataDirRecoveryError(format!(
+            "Unable to probe {}: {}",
+            lock_path, e
+        ))),
+    }
+}
+
+/// Parses the PORT=address written by ControlPortWriteToFile
+pub(crate) fn parse_control_port_file(contents: &str) -> Option<SocketAddr> {
+    contents
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("PORT="))
+        .and_then(|addr| addr.trim().parse().ok())
+}
+
+/// Checks a Tor control port is answering on addr
+fn is_control_port_alive(addr: &SocketAddr) -> bool {
+    let timeout = Duration::from_millis(500);
+    let stream = match TcpStream::connect_timeout(addr, timeout) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return false,
+    };
+    if writer.write_all(b"PROTOCOLINFO 1\r\n").is_err() {
+        return false;
+    }
+    let mut line = String::new();
+    match BufReader::new(stream).read_line(&mut line) {
+        Ok(_) => line.starts_with("250-PROTOCOLINFO"),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn setup_base_dir(name: &str) -> String {
+        let base_dir = format!("/tmp/sifir_rs_sdk_recovery/{}", name);
+        let _ = fs::remove_dir_all(&base_dir);
+        fs::create_dir_all(format!("{}/data", base_dir)).unwrap();
+        fs::create_dir_all(format!("{}/cache", base_dir)).unwrap();
+        base_dir
+    }
+
+    #[test]
+    fn recovers_killed_daemon_leftovers() {
+        let base_dir = setup_base_dir("killed");
+        // nothing listens on port 1
+        fs::write(format!("{}/ctl.info", base_dir), "PORT=127.0.0.1:1\n").unwrap();
+        fs::write(format!("{}/data/lock", base_dir), "").unwrap();
+        fs::write(
+            format!("{}/cache/cached-microdesc-consensus", base_dir),
+            "network-status-version 3 microdesc\nvalid-after",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/cache/cached-consensus", base_dir),
+            "network-status-version 3\n-----BEGIN SIGNATURE-----\nabc\n-----END SIGNATURE-----\n",
+        )
+        .unwrap();
+        fs::write(format!("{}/cache/cached-certs.tmp", base_dir), "").unwrap();
+
+        let actions = recover_data_dir(&base_dir).unwrap();
+        assert_eq!(actions.len(), 3);
+        assert!(!Path::new(&format!("{}/ctl.info", base_dir)).exists());
+        // an unlocked lock file is left for Tor to lock again
+        assert!(Path::new(&format!("{}/data/lock", base_dir)).exists());
+        assert!(!Path::new(&format!("{}/cache/cached-microdesc-consensus", base_dir)).exists());
+        assert!(Path::new(&format!("{}/cache/cached-consensus", base_dir)).exists());
+        // Clean directory needs no recovery
+        assert!(recover_data_dir(&base_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn errors_when_daemon_is_alive() {
+        let base_dir = setup_base_dir("alive");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0; 16];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(b"250-PROTOCOLINFO 1\r\n");
+            }
+        });
+        fs::write(format!("{}/ctl.info", base_dir), format!("PORT={}\n", addr)).unwrap();
+        fs::write(format!("{}/data/lock", base_dir), "").unwrap();
+        assert!(matches!(
+            recover_data_dir(&base_dir),
+            Err(TorErrors::DataDirectoryInUse(_))
+        ));
+        assert!(Path::new(&format!("{}/data/lock", base_dir)).exists());
+    }
+
+    #[test]
+    fn keeps_a_held_lock() {
+        let base_dir = setup_base_dir("locked");
+        let lock_path = format!("{}/data/lock", base_dir);
+        let lock = fs::File::create(&lock_path).unwrap();
+        lock.lock_exclusive().unwrap();
+        // no ctl.info, the daemon may not have written it yet
+        assert!(matches!(
+            recover_data_dir(&base_dir),
+            Err(TorErrors::DataDirectoryInUse(_))
+        ));
+        assert!(Path::new(&lock_path).exists());
+        lock.unlock().unwrap();
+        assert!(recover_data_dir(&base_dir).unwrap().is_empty());
+        assert!(Path::new(&lock_path).exists());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::onion::OnionAddress;
 use crate::TorErrors;
 use crate::RUNTIME;
 use socks::Socks5Stream;
@@ -28,6 +29,15 @@ pub trait DataObserver {
     fn on_error(&self, data: String);
 }
 
+/// Checks if a host:port (or bare host) target points to an onion service
+pub fn is_onion_target(target: &str) -> bool {
+    let host = match target.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => target,
+    };
+    host.to_lowercase().ends_with(".onion")
+}
+
 impl TcpSocksStream {
     /// Blocks indefinitely until connection established
     fn new(target: String, socks_proxy: String) -> Result<Self, TorErrors> {
@@ -43,11 +53,24 @@ impl TcpSocksStream {
     }
     /// New (connect) but with a timeout
     /// Blocks till connection established or timeout (in MS) expires
+    /// Onion targets are checked to be valid v3 addresses before a connection is attempted,
+    /// in onion only mode any other target is rejected
     pub fn new_timeout(
         target: String,
         socks_proxy: String,
         timeout_ms: u64,
+        onion_only: bool,
     ) -> Result<Self, TorErrors> {
+        if !is_onion_target(&target) {
+            if onion_only {
+                return Err(TorErrors::NonOnionTargetError(target));
+            }
+        } else if target.parse::<OnionAddress>()?.port().is_none() {
+            return Err(TorErrors::OnionAddressError(format!(
+                "{}: missing port",
+                target
+            )));
+        }
         let socks_future = (*RUNTIME)
             .lock()
             .unwrap()
@@ -171,7 +194,9 @@ impl TcpSocksStream {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{TorService, TorServiceParam};
+    use crate::{
+        testnet, HiddenServicePort, OwnedTorService, TorHiddenServiceParam, TorServiceParam,
+    };
     use serial_test::serial;
     use std::borrow::{Borrow, BorrowMut};
     use std::convert::TryInto;
@@ -179,40 +204,94 @@ mod tests {
     use std::sync::{Arc, Mutex};
 
     #[test]
-    #[serial(tor)]
-    fn connects_with_timeout() {
-        let service: TorService = TorServiceParam {
+    fn onion_only_rejects_clearnet_target() {
+        assert!(is_onion_target(
+            "kciybn4d4vuqvobdl2kdp3r2rudqbqvsymqwg4jomzft6m6gaibaf6yd.onion:50001"
+        ));
+        assert!(!is_onion_target("electrum.blockstream.info:50001"));
+        // No daemon is running on this port, rejection has to happen before connecting
+        let connection_result = TcpSocksStream::new_timeout(
+            "electrum.blockstream.info:50001".into(),
+            "127.0.0.1:1".into(),
+            1000,
+            true,
+        );
+        assert!(matches!(
+            connection_result,
+            Err(TorErrors::NonOnionTargetError(_))
+        ));
+        // Mistyped onion, also rejected before connecting
+        let connection_result = TcpSocksStream::new_timeout(
+            "kciybn4d4vuqvobdl2kdp3r2rudqbqvsymqwg4jomzft6m6gaibaf6ya.onion:50001".into(),
+            "127.0.0.1:1".into(),
+            1000,
+            false,
+        );
+        assert!(matches!(
+            connection_result,
+            Err(TorErrors::OnionAddressError(_))
+        ));
+    }
+
+    /// Starts a service on the local test network hosting an onion on port 50001 that answers
+    /// every line it receives with a json rpc reply, like the electrum servers these tests used
+    fn rpc_onion_on_local_network(local_port: u16) -> (OwnedTorService, String) {
+        let mut owned_node: OwnedTorService = TorServiceParam {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk/"),
-            bootstrap_timeout_ms: Some(45000),
+            bootstrap_timeout_ms: Some(180000),
+            test_network: Some(testnet::shared_network_param()),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
-        let mut _owned_node = service.into_owned_node();
-        let target = "udfpzbte2hommnvag5f3qlouqkhvp3xybhlus2yvfeqdwlhjroe4bbyd.onion:60001";
+        let service_key = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                ports: vec![HiddenServicePort::local(50001, local_port)],
+                ..Default::default()
+            })
+            .unwrap();
+        let listener = std::net::TcpListener::bind(("127.0.0.1", local_port)).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                std::thread::spawn(move || {
+                    let lines = std::io::BufReader::new(stream.try_clone().unwrap()).lines();
+                    for _ in lines.take_while(|line| line.is_ok()) {
+                        let reply = "{\"jsonrpc\": \"2.0\", \"result\": {\"confirmed\": 0, \"unconfirmed\": 0}, \"id\": 1}\n";
+                        if stream.write_all(reply.as_bytes()).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (owned_node, service_key.onion_url.to_string())
+    }
+
+    #[test]
+    #[serial(tor)]
+    fn connects_with_timeout() {
+        let (mut owned_node, target) = rpc_onion_on_local_network(20003);
         // Connecting over Tor takes much longer than 20ms so this should panic
         // TODO improve this test
         let connection_result =
-            TcpSocksStream::new_timeout(target.into(), "127.0.0.1:19054".into(), 20);
+            TcpSocksStream::new_timeout(target, "127.0.0.1:19054".into(), 20, false);
         assert_eq!(connection_result.is_err(), true);
+        owned_node.shutdown().unwrap();
     }
 
     #[test]
     #[serial(tor)]
     fn can_send_and_observe_data() {
-        let service: TorService = TorServiceParam {
-            socks_port: Some(19054),
-            data_dir: String::from("/tmp/sifir_rs_sdk/"),
-            bootstrap_timeout_ms: Some(45000),
-        }
-        .try_into()
-        .unwrap();
-        let mut owned_node = service.into_owned_node().unwrap();
-        let target = "kciybn4d4vuqvobdl2kdp3r2rudqbqvsymqwg4jomzft6m6gaibaf6yd.onion:50001";
+        let (mut owned_node, target) = rpc_onion_on_local_network(20004);
         let msg = "{ \"id\": 1, \"method\": \"blockchain.scripthash.get_balance\", \"params\": [\"716decbe1660861c3d93906cb1d98ee68b154fd4d23aed9783859c1271b52a9c\"] }\n";
 
         let mut tcp_com =
-            TcpSocksStream::new_timeout(target.into(), "127.0.0.1:19054".into(), 7000).unwrap();
+            TcpSocksStream::new_timeout(target, "127.0.0.1:19054".into(), 7000, false).unwrap();
         struct Observer {
             pub count: Arc<Mutex<u16>>,
         }
@@ -253,19 +332,11 @@ mod tests {
     #[test]
     #[serial(tor)]
     fn can_read_ticks() {
-        let service: TorService = TorServiceParam {
-            socks_port: Some(19054),
-            data_dir: String::from("/tmp/sifir_rs_sdk/"),
-            bootstrap_timeout_ms: Some(45000),
-        }
-        .try_into()
-        .unwrap();
-        let mut owned_node = service.into_owned_node().unwrap();
-        let target = "kciybn4d4vuqvobdl2kdp3r2rudqbqvsymqwg4jomzft6m6gaibaf6yd.onion:50001";
+        let (mut owned_node, target) = rpc_onion_on_local_network(20005);
         let msg = "{ \"id\": 1, \"method\": \"blockchain.scripthash.get_balance\", \"params\": [\"716decbe1660861c3d93906cb1d98ee68b154fd4d23aed9783859c1271b52a9c\"] }\n";
 
         let mut tcp_com =
-            TcpSocksStream::new_timeout(target.into(), "127.0.0.1:19054".into(), 7000).unwrap();
+            TcpSocksStream::new_timeout(target, "127.0.0.1:19054".into(), 7000, false).unwrap();
 
         struct Observer {
             pub count: Arc<Mutex<u16>>,
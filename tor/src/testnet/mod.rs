@@ -0,0 +1,231 @@
+use crate::TorErrors;
+#[cfg(test)]
+use lazy_static::lazy_static;
+use logger::log::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// Points the embedded daemon at a private Tor network (TestingTorNetwork) instead of the public one
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TestNetworkParam {
+    /// DirAuthority lines of the private network's directory authorities
+    pub dir_authorities: Vec<String>,
+}
+
+/// Options shared by every node of the network, see chutney's torrc templates
+const COMMON_TORRC: &str = "TestingTorNetwork 1
+PathsNeededToBuildCircuits 0.67
+TestingDirAuthVoteExit *
+TestingDirAuthVoteHSDir *
+TestingDirAuthVoteGuard *
+TestingMinExitFlagThreshold 0
+V3AuthNIntervalsValid 2
+RunAsDaemon 0
+ShutdownWaitLength 2
+SocksPort 0
+Address 127.0.0.1
+AssumeReachable 1
+ProtocolWarnings 1
+SafeLogging 0
+LogTimeGranularity 1
+";
+
+/// A private Tor network of directory authorities and relays running on loopback.
+/// Nodes are separate `tor` processes (libtor can only run a single daemon per process),
+/// keys are generated with `tor-gencert`. Binaries default to the ones in PATH and can be
+/// overridden with the SIFIR_TOR_BIN and SIFIR_TOR_GENCERT_BIN env vars.
+/// Nodes are killed when the network is dropped and exit on their own if the process that
+/// started them dies.
+pub struct LocalTorNetwork {
+    pub base_dir: String,
+    dir_authorities: Vec<String>,
+    nodes: Vec<Child>,
+}
+
+impl LocalTorNetwork {
+    /// Spins up `authorities` directory authorities and `relays` exit relays in `base_dir`
+    /// Node n listens for OR connections on base_port + n and for directory requests on base_port + 100 + n
+    pub fn start(
+        base_dir: &str,
+        authorities: u16,
+        relays: u16,
+        base_port: u16,
+    ) -> Result<Self, TorErrors> {
+        let _ = fs::remove_dir_all(base_dir);
+        fs::create_dir_all(base_dir)?;
+        let mut network = LocalTorNetwork {
+            base_dir: String::from(base_dir),
+            dir_authorities: Vec::new(),
+            nodes: Vec::new(),
+        };
+
+        // Authorities keys and fingerprints are needed by every node's torrc so create them first
+        let mut node_configs = Vec::new();
+        for n in 0..authorities {
+            let nick = format!("test{:03}a", n);
+            let node_dir = format!("{}/{}", base_dir, nick);
+            let or_port = base_port + n;
+            let dir_port = base_port + 100 + n;
+            let v3ident = generate_authority_keys(&node_dir, dir_port)?;
+            let fingerprint = relay_fingerprint(&node_dir, &nick, or_port)?;
+            network.dir_authorities.push(format!(
+                "{} orport={} no-v2 v3ident={} 127.0.0.1:{} {}",
+                nick, or_port, v3ident, dir_port, fingerprint
+            ));
+            let authority_torrc = format!(
+                "AuthoritativeDirectory 1\nV3AuthoritativeDirectory 1\nContactInfo {}@sifir.test\nExitPolicy reject *:*\nTestingV3AuthInitialVotingInterval 2\n",
+                nick
+            );
+            node_configs.push((nick, node_dir, or_port, dir_port, authority_torrc));
+        }
+        for n in authorities..authorities + relays {
+            let nick = format!("test{:03}r", n);
+            let node_dir = format!("{}/{}", base_dir, nick);
+            fs::create_dir_all(&node_dir)?;
+            let relay_torrc = String::from(
+                "ExitRelay 1\nExitPolicy accept 127.0.0.0/8:*\nExitPolicyRejectPrivate 0\n",
+            );
+            node_configs.push((nick, node_dir, base_port + n, base_port + 100 + n, relay_torrc));
+        }
+
+        for (nick, node_dir, or_port, dir_port, role_torrc) in node_configs {
+            let torrc_path = format!("{}/torrc", node_dir);
+            let mut torrc = String::from(COMMON_TORRC);
+            torrc.push_str(&format!(
+                "DataDirectory {dir}\nPidFile {dir}/pid\nLog notice file {dir}/notice.log\nNickname {nick}\nORPort {or_port}\nDirPort {dir_port}\n__OwningControllerProcess {pid}\n",
+                dir = node_dir,
+                nick = nick,
+                or_port = or_port,
+                dir_port = dir_port,
+                pid = std::process::id()
+            ));
+            torrc.push_str(&role_torrc);
+            for dir_authority in network.dir_authorities.iter() {
+                torrc.push_str(&format!("DirAuthority {}\n", dir_authority));
+            }
+            fs::write(&torrc_path, torrc)?;
+            info!("Starting local Tor network node {}", nick);
+            let child = Command::new(tor_bin())
+                .arg("-f")
+                .arg(&torrc_path)
+                .stdout(Stdio::null())
+                .spawn()
+                .map_err(|e| TorErrors::TestNetworkError(format!("Error starting tor: {}", e)))?;
+            network.nodes.push(child);
+        }
+        Ok(network)
+    }
+
+    /// Param pointing an embedded daemon at this network
+    pub fn test_network_param(&self) -> TestNetworkParam {
+        TestNetworkParam {
+            dir_authorities: self.dir_authorities.clone(),
+        }
+    }
+
+    /// Kills all the nodes of the network
+    pub fn shutdown(&mut self) {
+        for node in self.nodes.iter_mut() {
+            let _ = node.kill();
+            let _ = node.wait();
+        }
+        self.nodes.clear();
+    }
+}
+
+impl Drop for LocalTorNetwork {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref SHARED_NETWORK: Mutex<Option<LocalTorNetwork>> = Mutex::new(None);
+}
+
+/// Param of the private network the test suite runs against, started on first use and
+/// left running until the test process exits
+#[cfg(test)]
+pub(crate) fn shared_network_param() -> TestNetworkParam {
+    let mut network = SHARED_NETWORK.lock().unwrap();
+    if network.is_none() {
+        *network = Some(
+            LocalTorNetwork::start("/tmp/sifir_rs_sdk_testnet", 3, 3, 25000).expect(
+                "Could not start the local Tor network, are tor and tor-gencert installed?",
+            ),
+        );
+    }
+    network.as_ref().unwrap().test_network_param()
+}
+
+fn tor_bin() -> String {
+    std::env::var("SIFIR_TOR_BIN").unwrap_or_else(|_| String::from("tor"))
+}
+
+fn tor_gencert_bin() -> String {
+    std::env::var("SIFIR_TOR_GENCERT_BIN").unwrap_or_else(|_| String::from("tor-gencert"))
+}
+
+/// Generates the authority identity, signing key and certificate returning the v3ident fingerprint
+fn generate_authority_keys(node_dir: &str, dir_port: u16) -> Result<String, TorErrors> {
+    let keys_dir = format!("{}/keys", node_dir);
+    fs::create_dir_all(&keys_dir)?;
+    let mut gencert = Command::new(tor_gencert_bin())
+        .arg("--create-identity-key")
+        .args(&["-m", "12"])
+        .args(&["-a", &format!("127.0.0.1:{}", dir_port)])
+        .args(&["-i", &format!("{}/authority_identity_key", keys_dir)])
+        .args(&["-s", &format!("{}/authority_signing_key", keys_dir)])
+        .args(&["-c", &format!("{}/authority_certificate", keys_dir)])
+        .args(&["--passphrase-fd", "0"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| TorErrors::TestNetworkError(format!("Error starting tor-gencert: {}", e)))?;
+    // Empty passphrase
+    if let Some(stdin) = gencert.stdin.as_mut() {
+        stdin.write_all(b"\n")?;
+    }
+    if !gencert.wait()?.success() {
+        return Err(TorErrors::TestNetworkError(String::from(
+            "tor-gencert failed",
+        )));
+    }
+    let certificate = fs::read_to_string(format!("{}/authority_certificate", keys_dir))?;
+    certificate
+        .lines()
+        .find_map(|l| l.strip_prefix("fingerprint "))
+        .map(|fp| fp.trim().to_string())
+        .ok_or_else(|| {
+            TorErrors::TestNetworkError(String::from("No fingerprint in authority certificate"))
+        })
+}
+
+/// Generates the relay identity key of a node returning its fingerprint
+fn relay_fingerprint(node_dir: &str, nick: &str, or_port: u16) -> Result<String, TorErrors> {
+    let output = Command::new(tor_bin())
+        .args(&["--quiet", "--ignore-missing-torrc", "--list-fingerprint"])
+        .args(&["--DataDirectory", node_dir])
+        .args(&["--ORPort", &or_port.to_string()])
+        .args(&["--Nickname", nick])
+        .output()
+        .map_err(|e| TorErrors::TestNetworkError(format!("Error starting tor: {}", e)))?;
+    // "nick 0123 4567 ..." is written to the fingerprint file
+    let fingerprint = fs::read_to_string(format!("{}/fingerprint", node_dir)).map_err(|e| {
+        TorErrors::TestNetworkError(format!(
+            "No fingerprint generated: {} {}",
+            e,
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    })?;
+    Ok(fingerprint
+        .trim()
+        .trim_start_matches(nick)
+        .split_whitespace()
+        .collect())
+}
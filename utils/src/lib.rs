@@ -8,10 +8,85 @@ pub fn get_proxied_client(socks_port: u16) -> Result<reqwest::Client, reqwest::E
     reqwest::Client::builder().proxy(proxy).build()
 }
 
+/// Error returned by the onion only client for urls that do not point to an onion service
+#[derive(Debug)]
+pub struct NonOnionTargetError(pub String);
+
+impl std::fmt::Display for NonOnionTargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Target is not an onion address: {}", self.0)
+    }
+}
+
+impl std::error::Error for NonOnionTargetError {}
+
+pub fn is_onion_url(url: &reqwest::Url) -> bool {
+    url.host_str()
+        .map(|host| host.to_lowercase().ends_with(".onion"))
+        .unwrap_or(false)
+}
+
+/// Proxied client that refuses to build requests (or follow redirects) to non onion urls
+pub struct OnionOnlyClient {
+    client: reqwest::Client,
+}
+
+impl OnionOnlyClient {
+    pub fn new(socks_port: u16) -> Result<Self, reqwest::Error> {
+        let proxy = reqwest::Proxy::all(
+            reqwest::Url::parse(format!("socks5h://127.0.0.1:{}", socks_port).as_str()).unwrap(),
+        )
+        .unwrap();
+        let redirect_policy = reqwest::redirect::Policy::custom(|attempt| {
+            if is_onion_url(attempt.url()) {
+                attempt.follow()
+            } else {
+                let target = attempt.url().to_string();
+                attempt.error(NonOnionTargetError(target))
+            }
+        });
+        let client = reqwest::Client::builder()
+            .proxy(proxy)
+            .redirect(redirect_policy)
+            .build()?;
+        Ok(OnionOnlyClient { client })
+    }
+    pub fn request(
+        &self,
+        method: reqwest::Method,
+        url: reqwest::Url,
+    ) -> Result<reqwest::RequestBuilder, NonOnionTargetError> {
+        if !is_onion_url(&url) {
+            return Err(NonOnionTargetError(url.to_string()));
+        }
+        Ok(self.client.request(method, url))
+    }
+    pub fn get(&self, url: reqwest::Url) -> Result<reqwest::RequestBuilder, NonOnionTargetError> {
+        self.request(reqwest::Method::GET, url)
+    }
+    pub fn post(&self, url: reqwest::Url) -> Result<reqwest::RequestBuilder, NonOnionTargetError> {
+        self.request(reqwest::Method::POST, url)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn onion_only_client_rejects_clearnet() {
+        let client = OnionOnlyClient::new(19054).unwrap();
+        let clearnet = reqwest::Url::parse("https://blockstream.info/api/blocks").unwrap();
+        assert!(client.get(clearnet).is_err());
+        let onion = reqwest::Url::parse(
+            "http://keybase5wmilwokqirssclfnsqrjdsi7jdir5wy7y7iu3tanwmtp6oid.onion",
+        )
+        .unwrap();
+        assert!(client.get(onion).is_ok());
+    }
 }